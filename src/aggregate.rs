@@ -0,0 +1,421 @@
+// BSD 2-Clause License
+//
+// Copyright (c) 2020, Andrea Giacomo Baldan
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+//   list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+//   this list of conditions and the following disclaimer in the documentation
+//   and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::index::Labels;
+use crate::sketch::Sketch;
+use crate::timeseries::{self, Aggregator, Record, TimeSeries};
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+
+// Split the series into groups sharing the same values of the given labels, each group keyed by
+// those values. Series lacking some of the labels are grouped on the ones they have.
+pub fn group_by<'a>(
+    series: Vec<&'a TimeSeries>,
+    labels: &[String],
+) -> BTreeMap<Labels, Vec<&'a TimeSeries>> {
+    let mut groups: BTreeMap<Labels, Vec<&TimeSeries>> = BTreeMap::new();
+    for ts in series {
+        let key = labels
+            .iter()
+            .filter_map(|l| ts.labels().get(l).map(|v| (l.clone(), v.clone())))
+            .collect();
+        groups.entry(key).or_default().push(ts);
+    }
+    return groups;
+}
+
+// Partial aggregations of the points of the series in every bucket, keyed by the bucket start
+pub type Buckets = BTreeMap<u128, Bucket>;
+
+// Mergeable state of the points falling in a bucket, enough to compute any aggregation over them
+// no matter how they're split across series and shards: merging the states of two sets of points
+// is the same as adding all of them to a single one. The variance is tracked as the sum of the
+// squared differences from the mean, merged as described by Chan et al., and first and last by
+// timestamp. Percentiles keep the values, exactly up to a limit and in a sketch beyond it.
+#[derive(Debug, Clone)]
+pub struct Bucket {
+    count: usize,
+    sum: f64,
+    m2: f64,
+    min: f64,
+    max: f64,
+    first: Record,
+    last: Record,
+    values: Option<Values>,
+}
+
+#[derive(Debug, Clone)]
+enum Values {
+    Exact(Vec<f64>),
+    Sketch(Sketch),
+}
+
+impl Bucket {
+    // A bucket holding a single point, keeping its value only if the aggregator needs it
+    pub fn new(r: Record, aggregator: Aggregator) -> Bucket {
+        let values = match aggregator {
+            Aggregator::Median | Aggregator::Percentile(_) => Some(Values::Exact(vec![r.value()])),
+            _ => None,
+        };
+        Bucket {
+            count: 1,
+            sum: r.value(),
+            m2: 0.0,
+            min: r.value(),
+            max: r.value(),
+            first: r.clone(),
+            last: r,
+            values,
+        }
+    }
+
+    // Add a point not older than any other of the bucket
+    pub fn add(&mut self, r: Record) {
+        let value = r.value();
+        let mean = self.sum / self.count as f64;
+        self.count += 1;
+        self.sum += value;
+        self.m2 += (value - mean) * (value - self.sum / self.count as f64);
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.last = r;
+        if let Some(values) = self.values.as_mut() {
+            values.add(value);
+        }
+    }
+
+    // Merge the points of another bucket, on equal timestamps first and last come from the one
+    // merged earlier
+    pub fn merge(&mut self, other: Bucket) {
+        let count = self.count + other.count;
+        let delta = other.sum / other.count as f64 - self.sum / self.count as f64;
+        self.m2 += other.m2 + delta * delta * (self.count * other.count) as f64 / count as f64;
+        self.count = count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        if other.first.timestamp() < self.first.timestamp() {
+            self.first = other.first;
+        }
+        if other.last.timestamp() > self.last.timestamp() {
+            self.last = other.last;
+        }
+        if let (Some(values), Some(other)) = (self.values.as_mut(), other.values) {
+            values.merge(other);
+        }
+    }
+
+    // The aggregation of the points of the bucket, percentiles are NaN if they're not valid or
+    // the aggregator differs from the one the bucket was created for
+    pub fn value(&self, aggregator: Aggregator) -> f64 {
+        let n = self.count as f64;
+        match aggregator {
+            Aggregator::Avg => return self.sum / n,
+            Aggregator::Sum => return self.sum,
+            Aggregator::Count => return n,
+            Aggregator::Min => return self.min,
+            Aggregator::Max => return self.max,
+            Aggregator::First => return self.first.value(),
+            Aggregator::Last => return self.last.value(),
+            Aggregator::Range => return self.max - self.min,
+            Aggregator::StdDev => return (self.m2 / n).sqrt(),
+            Aggregator::Variance => return self.m2 / n,
+            Aggregator::Median => return self.quantile(0.5),
+            Aggregator::Percentile(q) => return self.quantile(q),
+        }
+    }
+
+    fn quantile(&self, q: f64) -> f64 {
+        let estimate = match &self.values {
+            Some(Values::Exact(values)) => timeseries::percentile(values.clone(), q),
+            Some(Values::Sketch(sketch)) if (0.0..=1.0).contains(&q) => sketch.quantile(q),
+            _ => None,
+        };
+        return estimate.unwrap_or(f64::NAN);
+    }
+}
+
+impl Values {
+    fn add(&mut self, value: f64) {
+        match self {
+            Values::Exact(values) => values.push(value),
+            Values::Sketch(sketch) => sketch.add(value),
+        }
+        self.bound();
+    }
+
+    fn merge(&mut self, other: Values) {
+        match (&mut *self, other) {
+            (Values::Exact(values), Values::Exact(mut other)) => values.append(&mut other),
+            (Values::Sketch(sketch), Values::Sketch(other)) => sketch.merge(&other),
+            (Values::Sketch(sketch), Values::Exact(other)) => {
+                other.into_iter().for_each(|v| sketch.add(v))
+            }
+            (Values::Exact(values), Values::Sketch(mut other)) => {
+                values.iter().for_each(|v| other.add(*v));
+                *self = Values::Sketch(other);
+            }
+        }
+        self.bound();
+    }
+
+    // Move the values into a sketch once there are too many to keep them all
+    fn bound(&mut self) {
+        if let Values::Exact(values) = self {
+            if values.len() > timeseries::EXACT_PERCENTILE_POINTS {
+                let mut sketch = Sketch::new();
+                values.iter().for_each(|v| sketch.add(*v));
+                *self = Values::Sketch(sketch);
+            }
+        }
+    }
+}
+
+// Add the points of every series to buckets of the given interval, aligned to its multiples so
+// they line up across series. Series held by different shards are collected into buckets of
+// their own, merged before combining them.
+pub fn collect(
+    series: &[&TimeSeries],
+    range: Option<(u128, u128)>,
+    interval: u128,
+    aggregator: Aggregator,
+    buckets: &mut Buckets,
+) {
+    let (lo, hi) = range.unwrap_or((0, u128::MAX));
+    for ts in series {
+        for (start, bucket) in partials(ts.range_iter(lo..=hi), interval, aggregator) {
+            merge(buckets, start, bucket);
+        }
+    }
+}
+
+// Aggregate records sorted by timestamp over buckets of the given interval, aligned to its
+// multiples, walking them once. Return the start of each bucket with at least a point paired with
+// its value.
+pub fn bucketize<I>(records: I, interval: u128, aggregator: Aggregator) -> Vec<(u128, f64)>
+where
+    I: Iterator<Item = Record>,
+{
+    return partials(records, interval, aggregator)
+        .into_iter()
+        .map(|(start, bucket)| (start, bucket.value(aggregator)))
+        .collect();
+}
+
+// The state of every bucket of records sorted by timestamp, in time order
+fn partials<I>(records: I, interval: u128, aggregator: Aggregator) -> Vec<(u128, Bucket)>
+where
+    I: Iterator<Item = Record>,
+{
+    let mut buckets: Vec<(u128, Bucket)> = Vec::new();
+    for r in records {
+        let start = r.timestamp() - r.timestamp() % interval;
+        match buckets.last_mut() {
+            Some((s, bucket)) if *s == start => bucket.add(r),
+            _ => buckets.push((start, Bucket::new(r, aggregator))),
+        }
+    }
+    return buckets;
+}
+
+// Merge the buckets collected apart, in the order they're given so the result never depends on
+// which set of buckets was ready first
+pub fn merge_all(buckets: &mut Buckets, other: Buckets) {
+    for (start, bucket) in other {
+        merge(buckets, start, bucket);
+    }
+}
+
+fn merge(buckets: &mut Buckets, start: u128, bucket: Bucket) {
+    match buckets.entry(start) {
+        Entry::Occupied(mut e) => e.get_mut().merge(bucket),
+        Entry::Vacant(e) => {
+            e.insert(bucket);
+        }
+    }
+}
+
+// Aggregate the points of every bucket as if they all belonged to a single series. Return the
+// start of each bucket paired with its value.
+pub fn combine(buckets: Buckets, aggregator: Aggregator) -> Vec<(u128, f64)> {
+    return buckets
+        .into_iter()
+        .map(|(start, bucket)| (start, bucket.value(aggregator)))
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn series(name: &str, labels: &[(&str, &str)], points: &[(u128, f64)]) -> TimeSeries {
+        let labels = labels
+            .iter()
+            .map(|(l, v)| (l.to_string(), v.to_string()))
+            .collect();
+        let mut ts = TimeSeries::with_labels(name.to_string(), labels, None, None);
+        for (timestamp, value) in points {
+            ts.add_point(Record::with_timestamp(*timestamp, *value));
+        }
+        return ts;
+    }
+
+    fn aggregate(
+        series: &[&TimeSeries],
+        range: Option<(u128, u128)>,
+        interval: u128,
+        aggregator: Aggregator,
+    ) -> Vec<(u128, f64)> {
+        let mut buckets = Buckets::new();
+        collect(series, range, interval, aggregator, &mut buckets);
+        return combine(buckets, aggregator);
+    }
+
+    #[test]
+    fn test_group_by() {
+        let a = series("a", &[("host", "web-1"), ("dc", "eu")], &[]);
+        let b = series("b", &[("host", "web-2"), ("dc", "eu")], &[]);
+        let c = series("c", &[("host", "web-1"), ("dc", "us")], &[]);
+        let d = series("d", &[], &[]);
+        let groups = group_by(vec![&a, &b, &c, &d], &["dc".to_string()]);
+        let keys: Vec<Option<&String>> = groups.keys().map(|k| k.get("dc")).collect();
+        assert_eq!(
+            keys,
+            vec![None, Some(&"eu".to_string()), Some(&"us".to_string())]
+        );
+        let eu: Vec<&str> = groups
+            .values()
+            .nth(1)
+            .unwrap()
+            .iter()
+            .map(|ts| ts.name())
+            .collect();
+        assert_eq!(eu, vec!["a", "b"]);
+        // Without labels to group by every series falls in the same group
+        assert_eq!(group_by(vec![&a, &b, &c, &d], &[]).len(), 1);
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let a = series("a", &[], &[(0, 1.0), (5, 3.0), (10, 5.0), (25, 7.0)]);
+        let b = series("b", &[], &[(2, 4.0), (12, 6.0), (14, 8.0)]);
+        // Every point weighs the same, whichever series it belongs to
+        let avg = aggregate(&[&a, &b], None, 10, Aggregator::Avg);
+        assert_eq!(avg, vec![(0, 8.0 / 3.0), (10, 19.0 / 3.0), (20, 7.0)]);
+        let max = aggregate(&[&a, &b], None, 10, Aggregator::Max);
+        assert_eq!(max, vec![(0, 4.0), (10, 8.0), (20, 7.0)]);
+        let min = aggregate(&[&a, &b], Some((5, 12)), 10, Aggregator::Min);
+        assert_eq!(min, vec![(0, 3.0), (10, 5.0)]);
+        let count = aggregate(&[&a, &b], None, 10, Aggregator::Count);
+        assert_eq!(count, vec![(0, 3.0), (10, 3.0), (20, 1.0)]);
+        assert!(aggregate(&[], None, 10, Aggregator::Avg).is_empty());
+    }
+
+    #[test]
+    fn test_aggregate_collected_apart() {
+        let a = series("a", &[], &[(0, 1.0), (5, 3.0), (10, 5.0)]);
+        let b = series("b", &[], &[(2, 4.0), (12, 6.0), (14, 8.0)]);
+        // Series collected separately combine as if they were collected together
+        for aggregator in &[
+            Aggregator::Avg,
+            Aggregator::Count,
+            Aggregator::Max,
+            Aggregator::Last,
+        ] {
+            let mut buckets = Buckets::new();
+            collect(&[&a], None, 10, *aggregator, &mut buckets);
+            collect(&[&b], None, 10, *aggregator, &mut buckets);
+            assert_eq!(
+                combine(buckets, *aggregator),
+                aggregate(&[&a, &b], None, 10, *aggregator)
+            );
+        }
+    }
+
+    #[test]
+    fn test_aggregate_spreads() {
+        // Series with different spreads in the same bucket, aggregated over all of their points
+        let a = series("a", &[], &[(1, 1.0), (3, 9.0)]);
+        let b = series("b", &[], &[(0, 4.0), (2, 5.0)]);
+        let value = |aggregator| aggregate(&[&a, &b], None, 10, aggregator)[0].1;
+        assert_eq!(value(Aggregator::Range), 8.0);
+        assert_eq!(value(Aggregator::First), 4.0);
+        assert_eq!(value(Aggregator::Last), 9.0);
+        assert_eq!(value(Aggregator::Median), 4.5);
+        assert_eq!(value(Aggregator::Percentile(1.0)), 9.0);
+        assert!((value(Aggregator::Variance) - 8.1875).abs() < 1e-12);
+        assert!((value(Aggregator::StdDev) - 8.1875_f64.sqrt()).abs() < 1e-12);
+        // Whatever the order the buckets are merged in
+        for aggregator in &[Aggregator::Variance, Aggregator::First, Aggregator::Median] {
+            let mut apart = Buckets::new();
+            collect(&[&b], None, 10, *aggregator, &mut apart);
+            let mut buckets = Buckets::new();
+            collect(&[&a], None, 10, *aggregator, &mut buckets);
+            merge_all(&mut buckets, apart);
+            let merged = combine(buckets, *aggregator)[0].1;
+            assert!((merged - value(*aggregator)).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_aggregate_single_series() {
+        // A series aggregated on its own gives the same values whichever the query
+        let a = series(
+            "a",
+            &[],
+            &[(0, 1.0), (5, 3.0), (10, 5.0), (12, 0.5), (25, 7.0)],
+        );
+        let aggregators = [
+            Aggregator::Avg,
+            Aggregator::First,
+            Aggregator::Range,
+            Aggregator::StdDev,
+            Aggregator::Variance,
+            Aggregator::Percentile(0.3),
+        ];
+        for aggregator in &aggregators {
+            assert_eq!(
+                bucketize(a.iter(), 10, *aggregator),
+                aggregate(&[&a], None, 10, *aggregator)
+            );
+        }
+    }
+
+    #[test]
+    fn test_aggregate_many_values() {
+        // Percentiles of buckets with too many values to sort are estimated through a sketch
+        let points: Vec<(u128, f64)> = (0..30_000).map(|i| (i, (i % 1000) as f64)).collect();
+        let (a, b) = points.split_at(15_000);
+        let a = series("a", &[], a);
+        let b = series("b", &[], b);
+        let median = aggregate(&[&a, &b], None, 100_000, Aggregator::Median)[0].1;
+        assert!((median - 499.5).abs() <= 499.5 * 0.01);
+        // Single series as well
+        let median = bucketize(a.iter().chain(b.iter()), 100_000, Aggregator::Median)[0].1;
+        assert!((median - 499.5).abs() <= 499.5 * 0.01);
+    }
+}
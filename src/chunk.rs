@@ -0,0 +1,380 @@
+// BSD 2-Clause License
+//
+// Copyright (c) 2020, Andrea Giacomo Baldan
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+//   list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+//   this list of conditions and the following disclaimer in the documentation
+//   and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::summary::Summary;
+use crate::timeseries::Record;
+use serde::{Deserialize, Serialize};
+
+// A compressed block of consecutive points of a timeseries, encoded as described in the Facebook
+// Gorilla paper: timestamps are stored as delta of deltas and values XORed with the previous one,
+// both using variable-length codes, so regular series cost just a couple of bits per point.
+// The first and last timestamps are kept in clear to locate points without decoding, as well as a
+// summary of the values to aggregate them without decoding either.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Chunk {
+    count: usize,
+    first_ts: u128,
+    last_ts: u128,
+    data: Vec<u8>,
+    summary: Summary,
+}
+
+impl Chunk {
+    // Compress a non empty slice of records sorted by timestamp
+    pub fn encode(records: &[Record]) -> Chunk {
+        let mut w = BitWriter::new();
+        let mut prev_ts = 0;
+        let mut prev_delta: u128 = 0;
+        let mut prev_value: u64 = 0;
+        let mut window = None;
+        for (i, r) in records.iter().enumerate() {
+            let value = r.value().to_bits();
+            if i == 0 {
+                w.write_u128(r.timestamp());
+                w.write_bits(value, 64);
+            } else {
+                // Wrapping arithmetic makes every delta reversible, whatever the timestamps
+                let delta = r.timestamp().wrapping_sub(prev_ts);
+                write_dod(&mut w, delta.wrapping_sub(prev_delta) as i128);
+                window = write_xor(&mut w, value ^ prev_value, window);
+                prev_delta = delta;
+            }
+            prev_ts = r.timestamp();
+            prev_value = value;
+        }
+        Chunk {
+            count: records.len(),
+            first_ts: records[0].timestamp(),
+            last_ts: prev_ts,
+            data: w.bytes,
+            summary: Summary::of(records.iter().map(|r| r.value())).unwrap(),
+        }
+    }
+
+    pub fn decode(&self) -> Vec<Record> {
+        return self.iter().collect();
+    }
+
+    pub fn iter(&self) -> ChunkIter<'_> {
+        ChunkIter {
+            reader: BitReader::new(&self.data),
+            remaining: self.count,
+            first: true,
+            ts: 0,
+            delta: 0,
+            value: 0,
+            window: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        return self.count;
+    }
+
+    pub fn first_ts(&self) -> u128 {
+        return self.first_ts;
+    }
+
+    pub fn last_ts(&self) -> u128 {
+        return self.last_ts;
+    }
+
+    pub fn summary(&self) -> &Summary {
+        return &self.summary;
+    }
+}
+
+// Lazy decoder of the points of a chunk, in time order
+pub struct ChunkIter<'a> {
+    reader: BitReader<'a>,
+    remaining: usize,
+    first: bool,
+    ts: u128,
+    delta: u128,
+    value: u64,
+    window: Option<(u32, u32)>,
+}
+
+impl<'a> Iterator for ChunkIter<'a> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        if self.first {
+            self.first = false;
+            self.ts = self.reader.read_u128();
+            self.value = self.reader.read_bits(64);
+        } else {
+            let dod = read_dod(&mut self.reader);
+            self.delta = self.delta.wrapping_add(dod as u128);
+            self.ts = self.ts.wrapping_add(self.delta);
+            let (xor, window) = read_xor(&mut self.reader, self.window);
+            self.value ^= xor;
+            self.window = window;
+        }
+        return Some(Record::with_timestamp(self.ts, f64::from_bits(self.value)));
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        return (self.remaining, Some(self.remaining));
+    }
+}
+
+// Delta of deltas buckets, each one a prefix of set bits terminated by an unset one (but the
+// last) followed by the value in the given number of bits
+const DOD_BUCKETS: [(u64, u32, u32); 5] = [
+    (0b10, 2, 7),
+    (0b110, 3, 9),
+    (0b1110, 4, 12),
+    (0b11110, 5, 64),
+    (0b11111, 5, 128),
+];
+
+fn write_dod(w: &mut BitWriter, dod: i128) {
+    if dod == 0 {
+        w.write_bit(false);
+        return;
+    }
+    for (prefix, prefix_len, bits) in DOD_BUCKETS.iter() {
+        if *bits == 128 {
+            w.write_bits(*prefix, *prefix_len);
+            w.write_u128(dod as u128);
+            return;
+        }
+        let limit = 1_i128 << (bits - 1);
+        if dod >= -limit && dod < limit {
+            w.write_bits(*prefix, *prefix_len);
+            w.write_bits(dod as u64 & mask(*bits), *bits);
+            return;
+        }
+    }
+}
+
+fn read_dod(r: &mut BitReader) -> i128 {
+    let mut ones = 0;
+    while ones < 5 && r.read_bit() {
+        ones += 1;
+    }
+    if ones == 0 {
+        return 0;
+    }
+    let bits = DOD_BUCKETS[ones - 1].2;
+    if bits == 128 {
+        return r.read_u128() as i128;
+    }
+    // Sign extend the value read
+    let v = r.read_bits(bits);
+    return (((v << (64 - bits)) as i64) >> (64 - bits)) as i128;
+}
+
+// XORed values are stored as their meaningful bits only, between leading and trailing zeros.
+// When they fit in the window of meaningful bits of the previous value it is reused, otherwise a
+// new window is written: 5 bits of leading zeros and 6 bits of meaningful bits length.
+fn write_xor(w: &mut BitWriter, xor: u64, window: Option<(u32, u32)>) -> Option<(u32, u32)> {
+    if xor == 0 {
+        w.write_bit(false);
+        return window;
+    }
+    w.write_bit(true);
+    let leading = xor.leading_zeros().min(31);
+    let trailing = xor.trailing_zeros();
+    if let Some((prev_leading, prev_trailing)) = window {
+        if leading >= prev_leading && trailing >= prev_trailing {
+            w.write_bit(false);
+            let len = 64 - prev_leading - prev_trailing;
+            w.write_bits(xor >> prev_trailing, len);
+            return window;
+        }
+    }
+    let len = 64 - leading - trailing;
+    w.write_bit(true);
+    w.write_bits(leading as u64, 5);
+    w.write_bits((len - 1) as u64, 6);
+    w.write_bits(xor >> trailing, len);
+    return Some((leading, trailing));
+}
+
+fn read_xor(r: &mut BitReader, window: Option<(u32, u32)>) -> (u64, Option<(u32, u32)>) {
+    if !r.read_bit() {
+        return (0, window);
+    }
+    let reuse = r.read_bit();
+    let (leading, trailing) = match window {
+        Some(w) if !reuse => w,
+        _ => {
+            let leading = r.read_bits(5) as u32;
+            let len = r.read_bits(6) as u32 + 1;
+            (leading, 64 - leading - len)
+        }
+    };
+    let len = 64 - leading - trailing;
+    return (r.read_bits(len) << trailing, Some((leading, trailing)));
+}
+
+fn mask(bits: u32) -> u64 {
+    if bits >= 64 {
+        return u64::MAX;
+    }
+    return (1 << bits) - 1;
+}
+
+// Bit-level writer, bits are packed most significant first
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            bytes: Vec::new(),
+            used: 8,
+        }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 8 {
+            self.bytes.push(0);
+            self.used = 0;
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+        }
+        self.used += 1;
+    }
+
+    // Write the lowest n bits of value
+    fn write_bits(&mut self, value: u64, n: u32) {
+        for i in (0..n).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    fn write_u128(&mut self, value: u128) {
+        self.write_bits((value >> 64) as u64, 64);
+        self.write_bits(value as u64, 64);
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader { bytes, pos: 0 }
+    }
+
+    fn read_bit(&mut self) -> bool {
+        let byte = self.bytes[self.pos / 8];
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        return bit;
+    }
+
+    fn read_bits(&mut self, n: u32) -> u64 {
+        let mut value = 0;
+        for _ in 0..n {
+            value = (value << 1) | self.read_bit() as u64;
+        }
+        return value;
+    }
+
+    fn read_u128(&mut self) -> u128 {
+        let hi = self.read_bits(64) as u128;
+        let lo = self.read_bits(64) as u128;
+        return (hi << 64) | lo;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn roundtrip(records: &[Record]) -> Chunk {
+        let chunk = Chunk::encode(records);
+        let decoded = chunk.decode();
+        assert_eq!(decoded.len(), records.len());
+        for (a, b) in decoded.iter().zip(records) {
+            assert_eq!(a.timestamp(), b.timestamp());
+            assert_eq!(a.value().to_bits(), b.value().to_bits());
+        }
+        return chunk;
+    }
+
+    #[test]
+    fn test_chunk_regular_series() {
+        let records: Vec<Record> = (0..1000)
+            .map(|i| Record::with_timestamp(1_600_000_000_000 + i * 1000, 21.5))
+            .collect();
+        let chunk = roundtrip(&records);
+        assert_eq!(chunk.len(), 1000);
+        assert_eq!(chunk.first_ts(), 1_600_000_000_000);
+        assert_eq!(chunk.last_ts(), 1_600_000_999_000);
+        // Constant interval and value take 2 bits per point
+        assert!(chunk.data.len() < 300);
+        // Nothing but the encoded points and a fixed size header
+        let size = bincode::serialize(&chunk).unwrap().len();
+        assert!(size <= chunk.data.len() + 96);
+    }
+
+    #[test]
+    fn test_chunk_irregular_series() {
+        let mut ts = 1_600_000_000_000_u128;
+        let mut records = Vec::new();
+        for i in 0..500_u128 {
+            ts += (i * i * 7919) % 100_000;
+            let value = ((i * 31) % 97) as f64 * 1.37 - 20.0;
+            records.push(Record::with_timestamp(ts, value));
+        }
+        roundtrip(&records);
+    }
+
+    #[test]
+    fn test_chunk_extreme_values() {
+        let records = vec![
+            Record::with_timestamp(0, f64::NAN),
+            Record::with_timestamp(0, f64::INFINITY),
+            Record::with_timestamp(1, -0.0),
+            Record::with_timestamp(u64::MAX as u128 * 3, f64::MIN_POSITIVE),
+            Record::with_timestamp(u128::MAX, f64::MAX),
+            Record::with_timestamp(u128::MAX, 1.0),
+        ];
+        roundtrip(&records);
+    }
+
+    #[test]
+    fn test_chunk_single_point() {
+        let chunk = roundtrip(&[Record::with_timestamp(42, 12.98)]);
+        assert_eq!(chunk.first_ts(), 42);
+        assert_eq!(chunk.last_ts(), 42);
+    }
+}
@@ -0,0 +1,342 @@
+// BSD 2-Clause License
+//
+// Copyright (c) 2020, Andrea Giacomo Baldan
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+//   list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+//   this list of conditions and the following disclaimer in the documentation
+//   and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::protocol::HEADER_SIZE;
+use crate::timeseries::Retention;
+use crate::wal::FsyncPolicy;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+pub const USAGE: &str = "Usage: teaspoon [OPTIONS]
+
+Options:
+    -c, --config FILE         read the settings from a TOML file, flags override them
+    -b, --bind ADDR           address to listen on, can be repeated (default 127.0.0.1)
+    -p, --port PORT           port to listen on (default 29191)
+    -d, --data-dir DIR        directory of the snapshot and the write-ahead log, without one
+                              nothing is persisted (default none)
+        --fsync POLICY        always, everysec or never (default everysec)
+        --retention-age MS    default maximum age of the points of new series
+        --retention-count N   default number of points kept by new series
+        --max-clients N       maximum number of connected clients (default 1024)
+        --buffer-size BYTES   size of the buffer sockets are read into (default 4096)
+        --max-events N        maximum number of events handled per poll (default 1024)
+        --max-frame-size BYTES
+                              maximum size of a request (default 16777216)
+        --max-input-buffer BYTES
+                              maximum size of the requests buffered for a client (default 33554432)
+        --max-output-buffer BYTES
+                              maximum size of the responses queued for a client (default 67108864)
+        --workers N           number of threads owning a shard of the series each (default 1)
+        --log-level LEVEL     off, error, warn, info, debug or trace (default info)
+    -h, --help                print this help
+";
+
+// Settings of the server, every one of them has a default and can be set in the configuration
+// file or on the command line
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    pub bind: Vec<SocketAddr>,
+    // Without a data directory the keyspace lives in memory only
+    pub data_dir: Option<PathBuf>,
+    pub fsync: FsyncPolicy,
+    pub retention: Option<Retention>,
+    pub max_clients: usize,
+    pub buffer_size: usize,
+    pub max_events: usize,
+    pub max_frame_size: usize,
+    pub max_input_buffer: usize,
+    pub max_output_buffer: usize,
+    pub workers: usize,
+    pub log_level: LevelFilter,
+}
+
+// Raw settings as read from the configuration file or the command line, all optional and not yet
+// validated. Keys of the file are the long flags with underscores in place of dashes.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    bind: Option<Vec<String>>,
+    port: Option<u16>,
+    data_dir: Option<String>,
+    fsync: Option<String>,
+    retention_age: Option<u64>,
+    retention_count: Option<usize>,
+    max_clients: Option<usize>,
+    buffer_size: Option<usize>,
+    max_events: Option<usize>,
+    max_frame_size: Option<usize>,
+    max_input_buffer: Option<usize>,
+    max_output_buffer: Option<usize>,
+    workers: Option<usize>,
+    log_level: Option<String>,
+}
+
+impl Settings {
+    // Settings of the other ones take precedence
+    fn merge(self, other: Settings) -> Settings {
+        Settings {
+            bind: other.bind.or(self.bind),
+            port: other.port.or(self.port),
+            data_dir: other.data_dir.or(self.data_dir),
+            fsync: other.fsync.or(self.fsync),
+            retention_age: other.retention_age.or(self.retention_age),
+            retention_count: other.retention_count.or(self.retention_count),
+            max_clients: other.max_clients.or(self.max_clients),
+            buffer_size: other.buffer_size.or(self.buffer_size),
+            max_events: other.max_events.or(self.max_events),
+            max_frame_size: other.max_frame_size.or(self.max_frame_size),
+            max_input_buffer: other.max_input_buffer.or(self.max_input_buffer),
+            max_output_buffer: other.max_output_buffer.or(self.max_output_buffer),
+            workers: other.workers.or(self.workers),
+            log_level: other.log_level.or(self.log_level),
+        }
+    }
+}
+
+impl Config {
+    // Build the configuration from the command line arguments, program name excluded. A file given
+    // with --config is read first, any other flag overrides its settings.
+    pub fn from_args(args: &[String]) -> Result<Config, String> {
+        let mut file = None;
+        let mut flags = Settings::default();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let mut value = || {
+                return args
+                    .next()
+                    .ok_or_else(|| format!("Missing value for {}", flag));
+            };
+            match flag.as_str() {
+                "-c" | "--config" => file = Some(value()?.clone()),
+                "-b" | "--bind" => flags
+                    .bind
+                    .get_or_insert_with(Vec::new)
+                    .push(value()?.clone()),
+                "-p" | "--port" => flags.port = Some(number(flag, value()?)?),
+                "-d" | "--data-dir" => flags.data_dir = Some(value()?.clone()),
+                "--fsync" => flags.fsync = Some(value()?.clone()),
+                "--retention-age" => flags.retention_age = Some(number(flag, value()?)?),
+                "--retention-count" => flags.retention_count = Some(number(flag, value()?)?),
+                "--max-clients" => flags.max_clients = Some(number(flag, value()?)?),
+                "--buffer-size" => flags.buffer_size = Some(number(flag, value()?)?),
+                "--max-events" => flags.max_events = Some(number(flag, value()?)?),
+                "--max-frame-size" => flags.max_frame_size = Some(number(flag, value()?)?),
+                "--max-input-buffer" => flags.max_input_buffer = Some(number(flag, value()?)?),
+                "--max-output-buffer" => flags.max_output_buffer = Some(number(flag, value()?)?),
+                "--workers" => flags.workers = Some(number(flag, value()?)?),
+                "--log-level" => flags.log_level = Some(value()?.clone()),
+                _ => return Err(format!("Unknown option {}, see --help", flag)),
+            }
+        }
+        let settings = match file {
+            Some(path) => {
+                let content = fs::read_to_string(&path)
+                    .map_err(|e| format!("Cannot read the configuration file {}: {}", path, e))?;
+                Config::parse(&content)
+                    .map_err(|e| format!("Invalid configuration file {}: {}", path, e))?
+                    .merge(flags)
+            }
+            None => flags,
+        };
+        return Config::new(settings);
+    }
+
+    fn parse(content: &str) -> Result<Settings, String> {
+        return toml::from_str(content).map_err(|e| e.to_string());
+    }
+
+    fn new(settings: Settings) -> Result<Config, String> {
+        let port = settings.port.unwrap_or(29191);
+        let bind = settings
+            .bind
+            .unwrap_or_else(|| vec!["127.0.0.1".to_string()])
+            .iter()
+            .map(|a| match IpAddr::from_str(a) {
+                Ok(ip) => return Ok(SocketAddr::new(ip, port)),
+                Err(_) => return Err(format!("Invalid bind address {}", a)),
+            })
+            .collect::<Result<Vec<SocketAddr>, String>>()?;
+        if bind.is_empty() {
+            return Err("At least one bind address is required".to_string());
+        }
+        let fsync = match settings.fsync.as_deref() {
+            Some("always") => FsyncPolicy::Always,
+            None | Some("everysec") => FsyncPolicy::EverySecond,
+            Some("never") => FsyncPolicy::Never,
+            Some(p) => {
+                return Err(format!(
+                    "Invalid fsync policy {}, must be always, everysec or never",
+                    p
+                ))
+            }
+        };
+        let retention = match (settings.retention_age, settings.retention_count) {
+            (Some(_), Some(_)) => {
+                return Err("Only one of retention age and count can be set".to_string())
+            }
+            (Some(0), None) | (None, Some(0)) => {
+                return Err("Retention must be greater than 0".to_string())
+            }
+            (Some(age), None) => Some(Retention::Age(age as u128)),
+            (None, Some(count)) => Some(Retention::Count(count)),
+            (None, None) => None,
+        };
+        let log_level = match settings.log_level {
+            Some(level) => {
+                LevelFilter::from_str(&level).map_err(|_| format!("Invalid log level {}", level))?
+            }
+            None => LevelFilter::Info,
+        };
+        let max_frame_size = positive("max frame size", settings.max_frame_size, 16 << 20)?;
+        let max_input_buffer = positive("max input buffer", settings.max_input_buffer, 32 << 20)?;
+        // A client must be able to send at least a whole frame, header included
+        if max_input_buffer < max_frame_size + HEADER_SIZE {
+            return Err(format!(
+                "Invalid max input buffer, must be at least the max frame size plus {} bytes",
+                HEADER_SIZE
+            ));
+        }
+        return Ok(Config {
+            bind,
+            data_dir: settings.data_dir.map(PathBuf::from),
+            fsync,
+            retention,
+            max_clients: positive("max clients", settings.max_clients, 1024)?,
+            buffer_size: positive("buffer size", settings.buffer_size, 4096)?,
+            max_events: positive("max events", settings.max_events, 1024)?,
+            max_frame_size,
+            max_input_buffer,
+            max_output_buffer: positive("max output buffer", settings.max_output_buffer, 64 << 20)?,
+            workers: positive("workers", settings.workers, 1)?,
+            log_level,
+        });
+    }
+}
+
+fn number<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    return value
+        .parse()
+        .map_err(|_| format!("Invalid value {} for {}", value, flag));
+}
+
+fn positive(name: &str, value: Option<usize>, default: usize) -> Result<usize, String> {
+    match value {
+        Some(0) => return Err(format!("Invalid {}, must be greater than 0", name)),
+        Some(v) => return Ok(v),
+        None => return Ok(default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn args(a: &[&str]) -> Vec<String> {
+        return a.iter().map(|s| s.to_string()).collect();
+    }
+
+    #[test]
+    fn test_config_defaults() {
+        let config = Config::from_args(&[]).unwrap();
+        assert_eq!(config.bind, vec!["127.0.0.1:29191".parse().unwrap()]);
+        assert_eq!(config.data_dir, None);
+        assert_eq!(config.fsync, FsyncPolicy::EverySecond);
+        assert_eq!(config.retention, None);
+        assert_eq!(config.buffer_size, 4096);
+        assert_eq!(config.max_frame_size, 16 << 20);
+        assert_eq!(config.workers, 1);
+        assert_eq!(config.log_level, LevelFilter::Info);
+    }
+
+    #[test]
+    fn test_config_file_and_flags() {
+        let file = Config::parse(
+            r#"
+            bind = ["0.0.0.0", "::1"]
+            port = 4000
+            fsync = "always"
+            retention_age = 60000
+            log_level = "debug"
+            "#,
+        )
+        .unwrap();
+        let flags = Settings {
+            port: Some(5000),
+            fsync: Some("never".to_string()),
+            ..Settings::default()
+        };
+        let config = Config::new(file.merge(flags)).unwrap();
+        assert_eq!(
+            config.bind,
+            vec![
+                "0.0.0.0:5000".parse().unwrap(),
+                "[::1]:5000".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.fsync, FsyncPolicy::Never);
+        assert_eq!(config.retention, Some(Retention::Age(60000)));
+        assert_eq!(config.log_level, LevelFilter::Debug);
+        let config = Config::from_args(&args(&[
+            "-b",
+            "10.0.0.1",
+            "--max-clients",
+            "8",
+            "--workers",
+            "4",
+        ]))
+        .unwrap();
+        assert_eq!(config.bind, vec!["10.0.0.1:29191".parse().unwrap()]);
+        assert_eq!(config.max_clients, 8);
+        assert_eq!(config.workers, 4);
+    }
+
+    #[test]
+    fn test_config_invalid() {
+        assert!(Config::parse("port = \"http\"").is_err());
+        assert!(Config::parse("unknown = 1").is_err());
+        for a in &[
+            &["--port"][..],
+            &["--port", "70000"],
+            &["--bind", "localhost"],
+            &["--fsync", "sometimes"],
+            &["--retention-age", "10", "--retention-count", "10"],
+            &["--buffer-size", "0"],
+            &["--workers", "0"],
+            &["--max-frame-size", "1024", "--max-input-buffer", "1024"],
+            &["--log-level", "loud"],
+            &["--verbose"],
+            &["--config", "/nonexistent/teaspoon.toml"],
+        ] {
+            assert!(Config::from_args(&args(a)).is_err(), "{:?}", a);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+// BSD 2-Clause License
+//
+// Copyright (c) 2020, Andrea Giacomo Baldan
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+//   list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+//   this list of conditions and the following disclaimer in the documentation
+//   and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::timeseries::Record;
+use serde::{Deserialize, Serialize};
+
+// Functions of the points of a range taking their timestamps into account, rates are per second.
+// Rate, irate and increase treat the series as a monotonic counter: a value lower than the
+// previous one is a reset, after which the counter restarted from zero, so the whole new value
+// counts as increase. Delta and derivative are meant for gauges and take values as they are.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum RateFunction {
+    // Average per-second increase over the range
+    Rate,
+    // Per-second increase between the last two points
+    Irate,
+    // Total increase over the range
+    Increase,
+    // Difference between the last and the first value
+    Delta,
+    // Per-second slope of the values, estimated with a least squares regression
+    Derivative,
+}
+
+impl RateFunction {
+    // Apply the function to records sorted by timestamp, None if there aren't enough points,
+    // at least two, or enough time between them
+    pub fn apply(&self, records: &[Record]) -> Option<f64> {
+        if records.len() < 2 {
+            return None;
+        }
+        let first = &records[0];
+        let last = &records[records.len() - 1];
+        match self {
+            RateFunction::Rate => {
+                return per_second(increase(records), last.timestamp() - first.timestamp());
+            }
+            RateFunction::Irate => {
+                let prev = &records[records.len() - 2];
+                return per_second(
+                    increase(&records[records.len() - 2..]),
+                    last.timestamp() - prev.timestamp(),
+                );
+            }
+            RateFunction::Increase => return Some(increase(records)),
+            RateFunction::Delta => return Some(last.value() - first.value()),
+            RateFunction::Derivative => return derivative(records),
+        }
+    }
+}
+
+// Apply the function over buckets of the given interval, aligned to its multiples. Each bucket
+// also takes the last point of the previous one, so no increase between buckets gets lost.
+// Buckets where the function has no value are skipped.
+pub fn buckets(records: &[Record], interval: u128, function: RateFunction) -> Vec<(u128, f64)> {
+    let mut buckets = Vec::new();
+    let mut lo = 0;
+    while lo < records.len() {
+        let start = records[lo].timestamp() - records[lo].timestamp() % interval;
+        let len = records[lo..]
+            .iter()
+            .take_while(|r| r.timestamp() - start < interval)
+            .count();
+        if let Some(value) = function.apply(&records[lo.saturating_sub(1)..lo + len]) {
+            buckets.push((start, value));
+        }
+        lo += len;
+    }
+    return buckets;
+}
+
+// Sum of the increments between consecutive points, detecting counter resets
+fn increase(records: &[Record]) -> f64 {
+    return records
+        .windows(2)
+        .map(|w| {
+            let (prev, cur) = (w[0].value(), w[1].value());
+            if cur < prev {
+                cur
+            } else {
+                cur - prev
+            }
+        })
+        .sum();
+}
+
+fn per_second(value: f64, millis: u128) -> Option<f64> {
+    if millis == 0 {
+        return None;
+    }
+    return Some(value * 1000.0 / millis as f64);
+}
+
+fn derivative(records: &[Record]) -> Option<f64> {
+    // Seconds relative to the first point, to keep the products small
+    let t0 = records[0].timestamp();
+    let points: Vec<(f64, f64)> = records
+        .iter()
+        .map(|r| ((r.timestamp() - t0) as f64 / 1000.0, r.value()))
+        .collect();
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let covariance: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f64 = points
+        .iter()
+        .map(|(x, _)| (x - mean_x) * (x - mean_x))
+        .sum();
+    if variance == 0.0 {
+        return None;
+    }
+    return Some(covariance / variance);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn records(points: &[(u128, f64)]) -> Vec<Record> {
+        return points
+            .iter()
+            .map(|(t, v)| Record::with_timestamp(*t, *v))
+            .collect();
+    }
+
+    #[test]
+    fn test_counter_reset() {
+        // The exporter restarted between 2000 and 3000
+        let r = records(&[
+            (0, 10.0),
+            (1000, 20.0),
+            (2000, 40.0),
+            (3000, 5.0),
+            (4000, 15.0),
+        ]);
+        assert_eq!(RateFunction::Increase.apply(&r), Some(45.0));
+        assert_eq!(RateFunction::Rate.apply(&r), Some(11.25));
+        assert_eq!(RateFunction::Irate.apply(&r), Some(10.0));
+        assert_eq!(RateFunction::Irate.apply(&r[..4]), Some(5.0));
+        assert_eq!(RateFunction::Delta.apply(&r), Some(5.0));
+    }
+
+    #[test]
+    fn test_counter_derivative() {
+        let r = records(&[(0, 1.0), (2000, 5.0), (4000, 9.0)]);
+        assert_eq!(RateFunction::Derivative.apply(&r), Some(2.0));
+        let r = records(&[(0, 1.0), (0, 5.0)]);
+        assert_eq!(RateFunction::Derivative.apply(&r), None);
+    }
+
+    #[test]
+    fn test_counter_not_enough_points() {
+        let r = records(&[(0, 1.0)]);
+        assert_eq!(RateFunction::Increase.apply(&r), None);
+        let r = records(&[(0, 1.0), (0, 2.0)]);
+        assert_eq!(RateFunction::Rate.apply(&r), None);
+        assert_eq!(RateFunction::Increase.apply(&r), Some(1.0));
+    }
+
+    #[test]
+    fn test_counter_buckets() {
+        let r = records(&[(0, 1.0), (500, 3.0), (1000, 4.0), (2500, 1.0), (3000, 2.0)]);
+        let increase = buckets(&r, 1000, RateFunction::Increase);
+        // The increase from 500 to 1000 belongs to the bucket at 1000, the reset to the one at 2000
+        assert_eq!(
+            increase,
+            vec![(0, 2.0), (1000, 1.0), (2000, 1.0), (3000, 1.0)]
+        );
+        let rate = buckets(&r, 1000, RateFunction::Rate);
+        assert_eq!(
+            rate,
+            vec![(0, 4.0), (1000, 2.0), (2000, 2.0 / 3.0), (3000, 2.0)]
+        );
+    }
+}
@@ -0,0 +1,1470 @@
+// BSD 2-Clause License
+//
+// Copyright (c) 2020, Andrea Giacomo Baldan
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+//   list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+//   this list of conditions and the following disclaimer in the documentation
+//   and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::aggregate;
+use crate::counter;
+use crate::index::{Labels, Matcher, NAME_LABEL};
+use crate::keyspace::Keyspace;
+use crate::protocol::{
+    OpCode, Status, TsAck, TsAddPoint, TsAggregate, TsAggregateReply, TsCreate, TsDelete, TsGroup,
+    TsHeader, TsInfo, TsInfoReply, TsMaddPoint, TsPacket, TsQuery, TsQueryReply, TsRate, TsSelect,
+    TsSelectReply, TsSeries, TsSnapshot,
+};
+use crate::timeseries::{
+    self, Aggregator, DuplicatePolicy, FillPolicy, Record, Retention, TimeSeries,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Error;
+
+// Series to aggregate grouped by the values of the group_by labels
+pub type Groups = BTreeMap<Labels, aggregate::Buckets>;
+
+// Maximum number of buckets of a query with a fill policy, as empty buckets are generated
+// regardless of the points stored
+const MAX_BUCKETS: u128 = 100_000;
+// Number of records of each frame of a streamed query, unless a page size is requested
+const STREAM_PAGE_SIZE: usize = 1024;
+
+// A failed command, carries the status to reply with and a human-readable explanation of the
+// failure
+struct Failure {
+    status: Status,
+    message: String,
+}
+
+impl Failure {
+    fn new(status: Status, message: String) -> Failure {
+        Failure { status, message }
+    }
+
+    fn not_found(name: &str) -> Failure {
+        return Failure::new(Status::TsNotFount, format!("Series {} not found", name));
+    }
+
+    fn duplicate(name: &str, timestamp: u128) -> Failure {
+        return Failure::new(
+            Status::TsDuplicate,
+            format!("Series {} already has a point at {}", name, timestamp),
+        );
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Failure {
+        return Failure::new(
+            Status::TsServerError,
+            format!("Write-ahead log error: {}", e),
+        );
+    }
+}
+
+// Position of a paginated query, encoded as the opaque continuation token handed to clients. Points
+// sharing the timestamp of the last one returned are told apart counting how many of them have
+// already been returned.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    reverse: bool,
+    timestamp: u128,
+    seen: usize,
+    remaining: Option<usize>,
+}
+
+impl Cursor {
+    fn encode(&self) -> Vec<u8> {
+        // Serialization of plain structs into a vector can't fail
+        return bincode::serialize(self).unwrap();
+    }
+
+    fn decode(token: &[u8], reverse: bool) -> Result<Cursor, Failure> {
+        match bincode::deserialize::<Cursor>(token) {
+            Ok(cursor) if cursor.reverse == reverse => return Ok(cursor),
+            _ => {
+                return Err(Failure::new(
+                    Status::TsBadRequest,
+                    "Invalid cursor".to_string(),
+                ))
+            }
+        }
+    }
+}
+
+// A streamed query in progress, each frame is produced on demand resuming from the cursor of the
+// previous one, so the records of the whole result are never all held at once
+pub struct Stream {
+    header: TsHeader,
+    query: TsQuery,
+    last: bool,
+    ended: bool,
+}
+
+impl Stream {
+    // The series streamed
+    pub fn name(&self) -> &str {
+        return &self.query.name;
+    }
+
+    // Whether the last frame, the end-of-stream or an error one, has been produced
+    pub fn is_over(&self) -> bool {
+        return self.ended;
+    }
+
+    // The next frame of the stream, None once the end-of-stream frame has been produced. Failures
+    // are replied with an error frame which ends the stream as well.
+    pub fn next_frame(&mut self, keyspace: &Keyspace) -> Option<Vec<u8>> {
+        if self.ended {
+            return None;
+        }
+        if self.last {
+            self.ended = true;
+            return Some(reply(&self.header, TsQueryReply::end_of_stream()));
+        }
+        match query(keyspace, &self.query) {
+            Ok(r) => {
+                match &r.cursor {
+                    Some(cursor) => self.query.cursor = Some(cursor.clone()),
+                    None => self.last = true,
+                }
+                return Some(reply(&self.header, r));
+            }
+            Err(f) => {
+                self.ended = true;
+                return Some(reply(
+                    &self.header,
+                    TsQueryReply::error(f.status, f.message),
+                ));
+            }
+        }
+    }
+}
+
+// Start a stream if the request is a streamed query, any other request is to be dispatched
+pub fn stream(buf: &[u8]) -> Option<Stream> {
+    let header = TsHeader::from_binary(buf).ok()?;
+    if header.opcode() != Some(OpCode::OpTsQuery) {
+        return None;
+    }
+    match decode::<TsQuery>(buf) {
+        Ok(query) if query.stream => {
+            return Some(Stream {
+                header,
+                query,
+                last: false,
+                ended: false,
+            })
+        }
+        _ => return None,
+    }
+}
+
+// Decode a single request packet, run the command it carries against the keyspace and return the
+// encoded response to be sent back to the client
+pub fn dispatch(keyspace: &mut Keyspace, buf: &[u8]) -> Vec<u8> {
+    let header = match TsHeader::from_binary(buf) {
+        Ok(h) => h,
+        // Without an header there's no way to tell what the request was, nothing to reply
+        Err(_) => return Vec::new(),
+    };
+    let opcode = match header.opcode() {
+        // Error frames only flow from the server to the clients
+        Some(OpCode::OpTsError) | None => {
+            let message = format!("Unknown command 0x{:02x}", header.byte());
+            return reply(&header, TsAck::error(Status::TsUnknownCmd, message));
+        }
+        Some(op) => op,
+    };
+    match opcode {
+        OpCode::OpTsError => unreachable!(),
+        OpCode::OpTsCreate => {
+            let result = decode(buf).and_then(|p: TsCreate| create(keyspace, &p));
+            return reply(&header, ack(result));
+        }
+        OpCode::OpTsDelete => {
+            let result = decode(buf).and_then(|p: TsDelete| delete(keyspace, &p));
+            return reply(&header, ack(result));
+        }
+        OpCode::OpTsAddPoint => {
+            let result = decode(buf).and_then(|p: TsAddPoint| add_point(keyspace, &p));
+            return reply(&header, ack(result));
+        }
+        OpCode::OpTsMaddPoint => {
+            let result = decode(buf).and_then(|p: TsMaddPoint| madd_point(keyspace, &p));
+            return reply(&header, ack(result));
+        }
+        // The whole stream is replied at once here, the server rather produces a frame at a time
+        OpCode::OpTsQuery => {
+            if let Some(mut stream) = stream(buf) {
+                let mut response = Vec::new();
+                while let Some(mut frame) = stream.next_frame(keyspace) {
+                    response.append(&mut frame);
+                }
+                return response;
+            }
+            let result = decode(buf).and_then(|p: TsQuery| query(keyspace, &p));
+            let packet = match result {
+                Ok(r) => r,
+                Err(f) => TsQueryReply::error(f.status, f.message),
+            };
+            return reply(&header, packet);
+        }
+        OpCode::OpTsSnapshot => {
+            let result = decode(buf).and_then(|_: TsSnapshot| snapshot(keyspace));
+            return reply(&header, ack(result));
+        }
+        OpCode::OpTsSelect => {
+            let result = decode(buf).and_then(|p: TsSelect| select(keyspace, &p));
+            let packet = match result {
+                Ok(r) => r,
+                Err(f) => TsSelectReply::error(f.status, f.message),
+            };
+            return reply(&header, packet);
+        }
+        OpCode::OpTsInfo => {
+            let result = decode(buf).and_then(|p: TsInfo| info(keyspace, &p));
+            let packet = match result {
+                Ok(r) => r,
+                Err(f) => TsInfoReply::error(f.status, f.message),
+            };
+            return reply(&header, packet);
+        }
+        OpCode::OpTsRate => {
+            let result = decode(buf).and_then(|p: TsRate| rate(keyspace, &p));
+            let packet = match result {
+                Ok(r) => r,
+                Err(f) => TsQueryReply::error(f.status, f.message),
+            };
+            return reply(&header, packet);
+        }
+        OpCode::OpTsAggregate => {
+            let result = decode(buf).and_then(|p: TsAggregate| aggregate(keyspace, &p));
+            let packet = match result {
+                Ok(r) => r,
+                Err(f) => TsAggregateReply::error(f.status, f.message),
+            };
+            return reply(&header, packet);
+        }
+    }
+}
+
+fn create(keyspace: &mut Keyspace, p: &TsCreate) -> Result<(), Failure> {
+    match p.retention {
+        Some(Retention::Age(0)) | Some(Retention::Count(0)) => {
+            return Err(Failure::new(
+                Status::TsBadRequest,
+                format!(
+                    "Invalid retention {:?}, must be greater than 0",
+                    p.retention
+                ),
+            ))
+        }
+        _ => (),
+    }
+    for (label, value) in &p.labels {
+        // The name label is implicit, empty values are the same as a missing label
+        if label.is_empty() || label == NAME_LABEL || value.is_empty() {
+            return Err(Failure::new(
+                Status::TsBadRequest,
+                format!("Invalid label {}={:?}", label, value),
+            ));
+        }
+    }
+    if !keyspace.create(
+        p.name.clone(),
+        p.labels.clone(),
+        p.retention,
+        p.duplicate_policy,
+    )? {
+        return Err(Failure::new(
+            Status::TsExists,
+            format!("Series {} already exists", p.name),
+        ));
+    }
+    return Ok(());
+}
+
+fn delete(keyspace: &mut Keyspace, p: &TsDelete) -> Result<(), Failure> {
+    if !keyspace.delete(&p.name)? {
+        return Err(Failure::not_found(&p.name));
+    }
+    return Ok(());
+}
+
+fn add_point(keyspace: &mut Keyspace, p: &TsAddPoint) -> Result<(), Failure> {
+    let ts = keyspace
+        .get(&p.name)
+        .ok_or_else(|| Failure::not_found(&p.name))?;
+    let record = to_record(p);
+    if ts.rejects(record.timestamp()) {
+        return Err(Failure::duplicate(&p.name, record.timestamp()));
+    }
+    keyspace.add_points(vec![(p.name.clone(), record)])?;
+    return Ok(());
+}
+
+// Points of a batch are all validated before adding any of them, so a batch handled by a single
+// shard is either entirely stored or rejected
+fn madd_point(keyspace: &mut Keyspace, p: &TsMaddPoint) -> Result<(), Failure> {
+    let points = validate_batch(keyspace, p)?;
+    keyspace.add_points(points)?;
+    return Ok(());
+}
+
+fn validate_batch(keyspace: &Keyspace, p: &TsMaddPoint) -> Result<Vec<(String, Record)>, Failure> {
+    // Points without a timestamp get the current time, a millisecond apart from each other within
+    // a series so they're never duplicates of one another
+    let now = timeseries::now();
+    let mut assigned: HashMap<&str, u128> = HashMap::new();
+    let points: Vec<(String, Record)> = p
+        .points
+        .iter()
+        .map(|point| {
+            let timestamp = match point.timestamp {
+                Some(timestamp) => timestamp,
+                None => *assigned
+                    .entry(&point.name)
+                    .and_modify(|t| *t += 1)
+                    .or_insert(now),
+            };
+            return (
+                point.name.clone(),
+                Record::with_timestamp(timestamp, point.value),
+            );
+        })
+        .collect();
+    check_points(keyspace, &points)?;
+    return Ok(points);
+}
+
+// Points can be stored only in existing series, without duplicates of points of series that
+// block them, either already stored or within the same batch
+fn check_points(keyspace: &Keyspace, points: &[(String, Record)]) -> Result<(), Failure> {
+    let mut batch = HashSet::new();
+    for (name, record) in points {
+        let ts = keyspace.get(name).ok_or_else(|| Failure::not_found(name))?;
+        // Duplicates may also be within the batch itself
+        let timestamp = record.timestamp();
+        let fresh = batch.insert((name, timestamp));
+        let blocked = !fresh && ts.duplicate_policy() == Some(DuplicatePolicy::Block);
+        if blocked || ts.rejects(timestamp) {
+            return Err(Failure::duplicate(name, timestamp));
+        }
+    }
+    return Ok(());
+}
+
+// A batch split across shards is validated by each of them before any stores its part. Points
+// without a timestamp get the current time here, so the validated records are the ones stored.
+pub fn prepare_batch(keyspace: &Keyspace, p: &TsMaddPoint) -> Result<Vec<(String, Record)>, TsAck> {
+    return validate_batch(keyspace, p).map_err(|f| TsAck::error(f.status, f.message));
+}
+
+// Other clients may have written to the series of a part since it was validated, so its points
+// are checked again before storing any of them
+pub fn commit_batch(keyspace: &mut Keyspace, points: Vec<(String, Record)>) -> TsAck {
+    let commit = |keyspace: &mut Keyspace| -> Result<(), Failure> {
+        check_points(keyspace, &points)?;
+        keyspace.add_points(points)?;
+        return Ok(());
+    };
+    return ack(commit(keyspace));
+}
+
+fn snapshot(keyspace: &mut Keyspace) -> Result<(), Failure> {
+    keyspace.snapshot().map_err(|e| {
+        Failure::new(
+            Status::TsServerError,
+            format!("Cannot save snapshot: {}", e),
+        )
+    })?;
+    return Ok(());
+}
+
+pub fn snapshot_shard(keyspace: &mut Keyspace) -> TsAck {
+    return ack(snapshot(keyspace));
+}
+
+fn to_record(p: &TsAddPoint) -> Record {
+    match p.timestamp {
+        Some(timestamp) => return Record::with_timestamp(timestamp, p.value),
+        None => return Record::new(p.value),
+    }
+}
+
+fn query(keyspace: &Keyspace, p: &TsQuery) -> Result<TsQueryReply, Failure> {
+    let ts = keyspace
+        .get(&p.name)
+        .ok_or_else(|| Failure::not_found(&p.name))?;
+    let (lo, hi) = p.range.unwrap_or((0, u128::MAX));
+    let aggregation = match p.aggregation {
+        Some(a) => a,
+        None => return records(ts, p),
+    };
+    if p.page_size.is_some() || p.cursor.is_some() || p.stream {
+        return Err(Failure::new(
+            Status::TsBadRequest,
+            "Only raw records can be paginated".to_string(),
+        ));
+    }
+    validate(aggregation)?;
+    match p.interval {
+        Some(0) => {
+            return Err(Failure::new(
+                Status::TsBadRequest,
+                "Interval must be greater than 0".to_string(),
+            ))
+        }
+        Some(interval) => {
+            let buckets = aggregate::bucketize(ts.range_iter(lo..=hi), interval, aggregation);
+            let buckets = fill(&buckets, p.range, interval, p.fill)?;
+            return Ok(TsQueryReply::buckets(buckets));
+        }
+        None => {
+            let values = ts.aggregate(lo, hi, aggregation).into_iter().collect();
+            return Ok(TsQueryReply::values(values));
+        }
+    }
+}
+
+// Raw records of a query, a page of them if paginated. The offset only applies to the first page,
+// following ones resume from the cursor, which also tracks how many records are left to the limit.
+fn records(ts: &TimeSeries, p: &TsQuery) -> Result<TsQueryReply, Failure> {
+    let (mut lo, mut hi) = p.range.unwrap_or((0, u128::MAX));
+    let page_size = match p.page_size {
+        Some(0) => {
+            return Err(Failure::new(
+                Status::TsBadRequest,
+                "Page size must be greater than 0".to_string(),
+            ))
+        }
+        Some(size) => size,
+        None if p.stream => STREAM_PAGE_SIZE,
+        None => usize::MAX,
+    };
+    let (offset, limit, resume) = match &p.cursor {
+        Some(token) => {
+            let cursor = Cursor::decode(token, p.reverse)?;
+            if p.reverse {
+                hi = hi.min(cursor.timestamp);
+            } else {
+                lo = lo.max(cursor.timestamp);
+            }
+            (0, cursor.remaining, Some((cursor.timestamp, cursor.seen)))
+        }
+        None => (p.offset, p.limit, None),
+    };
+    let records: Box<dyn Iterator<Item = Record>> = if p.reverse {
+        Box::new(ts.range_rev(lo..=hi))
+    } else {
+        Box::new(ts.range_iter(lo..=hi))
+    };
+    // Records at the resume timestamp already returned by the previous pages come first
+    let (timestamp, seen) = resume.unwrap_or((0, 0));
+    let mut skipped = 0;
+    let mut records = records
+        .skip_while(|r| {
+            let skip = skipped < seen && r.timestamp() == timestamp;
+            skipped += skip as usize;
+            return skip;
+        })
+        .skip(offset)
+        .take(limit.unwrap_or(usize::MAX))
+        .peekable();
+    let page: Vec<Record> = records.by_ref().take(page_size).collect();
+    let last = match page.last() {
+        Some(last) if records.peek().is_some() => last.timestamp(),
+        _ => return Ok(TsQueryReply::page(page, None)),
+    };
+    let before = if last == timestamp { seen } else { 0 };
+    let cursor = Cursor {
+        reverse: p.reverse,
+        timestamp: last,
+        seen: before + page.iter().filter(|r| r.timestamp() == last).count(),
+        remaining: limit.map(|l| l - page.len()),
+    };
+    return Ok(TsQueryReply::page(page, Some(cursor.encode())));
+}
+
+fn info(keyspace: &Keyspace, p: &TsInfo) -> Result<TsInfoReply, Failure> {
+    let ts = keyspace
+        .get(&p.name)
+        .ok_or_else(|| Failure::not_found(&p.name))?;
+    return Ok(TsInfoReply::info(ts));
+}
+
+fn rate(keyspace: &Keyspace, p: &TsRate) -> Result<TsQueryReply, Failure> {
+    let ts = keyspace
+        .get(&p.name)
+        .ok_or_else(|| Failure::not_found(&p.name))?;
+    let records = match p.range {
+        Some((lo, hi)) => ts.range(lo, hi),
+        None => ts.iter().collect(),
+    };
+    match p.interval {
+        Some(0) => {
+            return Err(Failure::new(
+                Status::TsBadRequest,
+                "Interval must be greater than 0".to_string(),
+            ))
+        }
+        Some(interval) => {
+            let buckets = counter::buckets(&records, interval, p.function);
+            let buckets = fill(&buckets, p.range, interval, p.fill)?;
+            return Ok(TsQueryReply::buckets(buckets));
+        }
+        None => {
+            let values = p.function.apply(&records).into_iter().collect();
+            return Ok(TsQueryReply::values(values));
+        }
+    }
+}
+
+fn select(keyspace: &Keyspace, p: &TsSelect) -> Result<TsSelectReply, Failure> {
+    let series = matching(keyspace, &p.matchers)?
+        .into_iter()
+        .map(|ts| TsSeries {
+            name: ts.name().to_string(),
+            labels: ts.labels().clone(),
+            records: match p.range {
+                Some((lo, hi)) => ts.range(lo, hi),
+                None => ts.iter().collect(),
+            },
+        })
+        .collect();
+    return Ok(TsSelectReply::series(series));
+}
+
+// The series of a shard matching a select, to be merged with the ones of the other shards
+pub fn select_shard(keyspace: &Keyspace, p: &TsSelect) -> TsSelectReply {
+    match select(keyspace, p) {
+        Ok(r) => return r,
+        Err(f) => return TsSelectReply::error(f.status, f.message),
+    }
+}
+
+fn aggregate(keyspace: &Keyspace, p: &TsAggregate) -> Result<TsAggregateReply, Failure> {
+    let groups = groups(keyspace, p)?;
+    return combine_groups(p, groups);
+}
+
+// Groups of series matching an aggregation, with the partial aggregation of their points in
+// every bucket
+fn groups(keyspace: &Keyspace, p: &TsAggregate) -> Result<Groups, Failure> {
+    if p.interval == 0 {
+        return Err(Failure::new(
+            Status::TsBadRequest,
+            "Interval must be greater than 0".to_string(),
+        ));
+    }
+    validate(p.aggregation)?;
+    let series = matching(keyspace, &p.matchers)?;
+    let mut groups = Groups::new();
+    for (labels, series) in aggregate::group_by(series, &p.group_by) {
+        let buckets = groups.entry(labels).or_default();
+        aggregate::collect(&series, p.range, p.interval, p.aggregation, buckets);
+    }
+    return Ok(groups);
+}
+
+fn combine_groups(p: &TsAggregate, groups: Groups) -> Result<TsAggregateReply, Failure> {
+    let mut result = Vec::new();
+    for (labels, buckets) in groups {
+        let buckets = aggregate::combine(buckets, p.aggregation);
+        let points = fill(&buckets, p.range, p.interval, p.fill)?;
+        result.push(TsGroup { labels, points });
+    }
+    return Ok(TsAggregateReply::groups(result));
+}
+
+// The groups of the series of a shard matching an aggregation, the ones of every shard are merged
+// before combining the series of each group
+pub fn aggregate_shard(keyspace: &Keyspace, p: &TsAggregate) -> Result<Groups, TsAggregateReply> {
+    return groups(keyspace, p).map_err(|f| TsAggregateReply::error(f.status, f.message));
+}
+
+// Merge the groups of every shard, always in shard order so the same query over the same points
+// yields the same result to the last bit, then combine them
+pub fn combine_shards(p: &TsAggregate, shards: Vec<Groups>) -> TsAggregateReply {
+    let mut groups = Groups::new();
+    for shard in shards {
+        for (labels, buckets) in shard {
+            aggregate::merge_all(groups.entry(labels).or_default(), buckets);
+        }
+    }
+    match combine_groups(p, groups) {
+        Ok(r) => return r,
+        Err(f) => return TsAggregateReply::error(f.status, f.message),
+    }
+}
+
+// Fill the gaps between the aggregated buckets, over the whole range if given or from the first to
+// the last bucket otherwise. Ranges spanning too many buckets are refused.
+fn fill(
+    buckets: &[(u128, f64)],
+    range: Option<(u128, u128)>,
+    interval: u128,
+    policy: FillPolicy,
+) -> Result<Vec<(u128, Option<f64>)>, Failure> {
+    let (lo, hi) = match (range, buckets.first(), buckets.last()) {
+        _ if policy == FillPolicy::None => (0, 0),
+        (Some(range), _, _) => range,
+        (None, Some(first), Some(last)) => (first.0, last.0),
+        _ => return Ok(Vec::new()),
+    };
+    if lo <= hi && hi / interval - lo / interval >= MAX_BUCKETS {
+        return Err(Failure::new(
+            Status::TsBadRequest,
+            format!("Too many buckets, at most {} can be filled", MAX_BUCKETS),
+        ));
+    }
+    return Ok(timeseries::fill(buckets, lo, hi, interval, policy));
+}
+
+fn validate(aggregator: Aggregator) -> Result<(), Failure> {
+    return aggregator
+        .validate()
+        .map_err(|message| Failure::new(Status::TsBadRequest, message));
+}
+
+// Select the series satisfying all the matchers of a request
+fn matching<'a>(
+    keyspace: &'a Keyspace,
+    matchers: &[Matcher],
+) -> Result<Vec<&'a TimeSeries>, Failure> {
+    // Without matchers the whole keyspace would be selected, it's most likely a mistake
+    if matchers.is_empty() {
+        return Err(Failure::new(
+            Status::TsBadRequest,
+            "At least one matcher is required".to_string(),
+        ));
+    }
+    return keyspace.select(matchers).map_err(|e| {
+        Failure::new(
+            Status::TsBadRequest,
+            format!("Invalid regular expression: {}", e),
+        )
+    });
+}
+
+// Deserialize the payload of a request, a malformed one is reported back as a bad request
+fn decode<T>(buf: &[u8]) -> Result<T, Failure>
+where
+    T: Serialize,
+    T: DeserializeOwned,
+{
+    match TsPacket::<T>::from_binary(buf) {
+        Ok(p) => return Ok(p.into_packet()),
+        Err(e) => {
+            return Err(Failure::new(
+                Status::TsBadRequest,
+                format!("Malformed payload: {}", e),
+            ))
+        }
+    }
+}
+
+fn ack(result: Result<(), Failure>) -> TsAck {
+    match result {
+        Ok(()) => return TsAck::ok(),
+        Err(f) => return TsAck::error(f.status, f.message),
+    }
+}
+
+// An error frame, not replying to any request, telling a client why it's being disconnected
+pub fn error_frame(status: Status, message: String) -> Vec<u8> {
+    // Serialization of plain structs into a vector can't fail
+    return TsPacket::new(OpCode::OpTsError, TsAck::error(status, message))
+        .to_binary()
+        .unwrap();
+}
+
+pub fn reply<T>(request: &TsHeader, packet: T) -> Vec<u8>
+where
+    T: Serialize,
+    T: DeserializeOwned,
+{
+    // Serialization of plain structs into a vector can't fail
+    return TsPacket::reply(request, packet).to_binary().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::counter::RateFunction;
+    use crate::index::{Labels, MatchOp};
+    use crate::protocol;
+
+    fn request<T>(opcode: OpCode, packet: T) -> Vec<u8>
+    where
+        T: Serialize,
+        T: DeserializeOwned,
+    {
+        return TsPacket::new(opcode, packet).to_binary().unwrap();
+    }
+
+    fn ack_of(response: &[u8]) -> TsAck {
+        let ack: TsPacket<TsAck> = TsPacket::from_binary(response).unwrap();
+        return ack.into_packet();
+    }
+
+    #[test]
+    fn test_dispatch_create() {
+        let mut ks = Keyspace::new();
+        let req = request(
+            OpCode::OpTsCreate,
+            TsCreate {
+                name: "test-ts".to_string(),
+                labels: Labels::new(),
+                retention: None,
+                duplicate_policy: None,
+            },
+        );
+        assert_eq!(ack_of(&dispatch(&mut ks, &req)), TsAck::ok());
+        let ack = ack_of(&dispatch(&mut ks, &req));
+        assert_eq!(ack.status, Status::TsExists);
+        assert!(ack.error.is_some());
+    }
+
+    #[test]
+    fn test_dispatch_create_bad_retention() {
+        let mut ks = Keyspace::new();
+        let req = request(
+            OpCode::OpTsCreate,
+            TsCreate {
+                name: "test-ts".to_string(),
+                labels: Labels::new(),
+                retention: Some(Retention::Age(0)),
+                duplicate_policy: None,
+            },
+        );
+        assert_eq!(
+            ack_of(&dispatch(&mut ks, &req)).status,
+            Status::TsBadRequest
+        );
+        assert!(ks.get("test-ts").is_none());
+    }
+
+    fn add_point(name: &str, timestamp: u128, value: f64) -> TsAddPoint {
+        TsAddPoint {
+            name: name.to_string(),
+            timestamp: Some(timestamp),
+            value,
+        }
+    }
+
+    fn query_reply(ks: &mut Keyspace, query: TsQuery) -> TsQueryReply {
+        let response = dispatch(ks, &request(OpCode::OpTsQuery, query));
+        let reply: TsPacket<TsQueryReply> = TsPacket::from_binary(&response).unwrap();
+        return reply.into_packet();
+    }
+
+    #[test]
+    fn test_dispatch_add_point_and_query() {
+        let mut ks = Keyspace::new();
+        ks.create("test-ts".to_string(), Labels::new(), None, None)
+            .unwrap();
+        let req = request(
+            OpCode::OpTsAddPoint,
+            TsAddPoint {
+                name: "test-ts".to_string(),
+                timestamp: None,
+                value: 12.98,
+            },
+        );
+        assert_eq!(ack_of(&dispatch(&mut ks, &req)), TsAck::ok());
+        let reply = query_reply(
+            &mut ks,
+            TsQuery {
+                name: "test-ts".to_string(),
+                range: None,
+                aggregation: None,
+                interval: None,
+                fill: FillPolicy::None,
+                reverse: false,
+                offset: 0,
+                limit: None,
+                page_size: None,
+                cursor: None,
+                stream: false,
+            },
+        );
+        assert_eq!(reply.status, Status::TsOk);
+        assert_eq!(reply.records.len(), 1);
+    }
+
+    #[test]
+    fn test_dispatch_madd_point() {
+        let mut ks = Keyspace::new();
+        ks.create("ts-1".to_string(), Labels::new(), None, None)
+            .unwrap();
+        ks.create("ts-2".to_string(), Labels::new(), None, None)
+            .unwrap();
+        let req = request(
+            OpCode::OpTsMaddPoint,
+            TsMaddPoint {
+                points: vec![
+                    add_point("ts-1", 10, 12.98),
+                    add_point("ts-2", 10, 19.63),
+                    add_point("ts-1", 20, 11.28),
+                ],
+            },
+        );
+        assert_eq!(ack_of(&dispatch(&mut ks, &req)), TsAck::ok());
+        assert_eq!(ks.get("ts-1").unwrap().len(), 2);
+        assert_eq!(ks.get("ts-2").unwrap().len(), 1);
+        // A single unknown series rejects the whole batch
+        let req = request(
+            OpCode::OpTsMaddPoint,
+            TsMaddPoint {
+                points: vec![add_point("ts-1", 30, 15.96), add_point("ts-3", 30, 1.0)],
+            },
+        );
+        assert_eq!(ack_of(&dispatch(&mut ks, &req)).status, Status::TsNotFount);
+        assert_eq!(ks.get("ts-1").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_commit_batch() {
+        let mut ks = Keyspace::new();
+        let block = Some(DuplicatePolicy::Block);
+        ks.create("ts-1".to_string(), Labels::new(), None, block)
+            .unwrap();
+        ks.create("ts-2".to_string(), Labels::new(), None, None)
+            .unwrap();
+        let batch = TsMaddPoint {
+            points: vec![add_point("ts-1", 10, 12.98), add_point("ts-2", 10, 19.63)],
+        };
+        // Points written in between are refused rather than silently dropped
+        let points = prepare_batch(&ks, &batch).unwrap();
+        ks.add_points(vec![("ts-1".to_string(), Record::with_timestamp(10, 1.0))])
+            .unwrap();
+        let ack = commit_batch(&mut ks, points);
+        assert_eq!(ack.status, Status::TsDuplicate);
+        assert_eq!(ks.get("ts-2").unwrap().len(), 0);
+        // As well as points of series deleted in between
+        let batch = TsMaddPoint {
+            points: vec![add_point("ts-2", 10, 19.63)],
+        };
+        let points = prepare_batch(&ks, &batch).unwrap();
+        ks.delete("ts-2").unwrap();
+        assert_eq!(commit_batch(&mut ks, points).status, Status::TsNotFount);
+    }
+
+    #[test]
+    fn test_dispatch_query_aggregation() {
+        let mut ks = Keyspace::new();
+        ks.create("test-ts".to_string(), Labels::new(), None, None)
+            .unwrap();
+        let req = request(
+            OpCode::OpTsMaddPoint,
+            TsMaddPoint {
+                points: vec![
+                    add_point("test-ts", 100, 12.98),
+                    add_point("test-ts", 200, 19.63),
+                    add_point("test-ts", 600, 11.28),
+                    add_point("test-ts", 700, 15.96),
+                ],
+            },
+        );
+        dispatch(&mut ks, &req);
+        let reply = query_reply(
+            &mut ks,
+            TsQuery {
+                name: "test-ts".to_string(),
+                range: Some((150, 650)),
+                aggregation: None,
+                interval: None,
+                fill: FillPolicy::None,
+                reverse: false,
+                offset: 0,
+                limit: None,
+                page_size: None,
+                cursor: None,
+                stream: false,
+            },
+        );
+        assert_eq!(reply.records.len(), 2);
+        let reply = query_reply(
+            &mut ks,
+            TsQuery {
+                name: "test-ts".to_string(),
+                range: None,
+                aggregation: None,
+                interval: None,
+                fill: FillPolicy::None,
+                reverse: true,
+                offset: 1,
+                limit: Some(2),
+                page_size: None,
+                cursor: None,
+                stream: false,
+            },
+        );
+        let values: Vec<f64> = reply.records.iter().map(|r| r.value()).collect();
+        assert_eq!(values, vec![11.28, 19.63]);
+        let reply = query_reply(
+            &mut ks,
+            TsQuery {
+                name: "test-ts".to_string(),
+                range: Some((150, 650)),
+                aggregation: Some(Aggregator::Max),
+                interval: None,
+                fill: FillPolicy::None,
+                reverse: false,
+                offset: 0,
+                limit: None,
+                page_size: None,
+                cursor: None,
+                stream: false,
+            },
+        );
+        assert_eq!(reply.values, vec![19.63]);
+        let reply = query_reply(
+            &mut ks,
+            TsQuery {
+                name: "test-ts".to_string(),
+                range: None,
+                aggregation: Some(Aggregator::Avg),
+                interval: Some(500),
+                fill: FillPolicy::None,
+                reverse: false,
+                offset: 0,
+                limit: None,
+                page_size: None,
+                cursor: None,
+                stream: false,
+            },
+        );
+        assert_eq!(
+            reply.buckets,
+            vec![(0, Some(16.305)), (500, Some(13.620000000000001))]
+        );
+        let reply = query_reply(
+            &mut ks,
+            TsQuery {
+                name: "test-ts".to_string(),
+                range: None,
+                aggregation: Some(Aggregator::Avg),
+                interval: Some(0),
+                fill: FillPolicy::None,
+                reverse: false,
+                offset: 0,
+                limit: None,
+                page_size: None,
+                cursor: None,
+                stream: false,
+            },
+        );
+        assert_eq!(reply.status, Status::TsBadRequest);
+    }
+
+    #[test]
+    fn test_dispatch_query_percentile() {
+        let mut ks = Keyspace::new();
+        ks.create("latency".to_string(), Labels::new(), None, None)
+            .unwrap();
+        let points = (0..100)
+            .map(|i| add_point("latency", i, i as f64))
+            .collect();
+        dispatch(
+            &mut ks,
+            &request(OpCode::OpTsMaddPoint, TsMaddPoint { points }),
+        );
+        let query = |range, q, interval| TsQuery {
+            name: "latency".to_string(),
+            range,
+            aggregation: Some(Aggregator::Percentile(q)),
+            interval,
+            fill: FillPolicy::None,
+            reverse: false,
+            offset: 0,
+            limit: None,
+            page_size: None,
+            cursor: None,
+            stream: false,
+        };
+        let reply = query_reply(&mut ks, query(None, 0.99, None));
+        assert_eq!(reply.values, vec![98.01]);
+        let reply = query_reply(&mut ks, query(Some((0, 10)), 0.5, None));
+        assert_eq!(reply.values, vec![5.0]);
+        let reply = query_reply(&mut ks, query(None, 0.5, Some(50)));
+        assert_eq!(reply.buckets, vec![(0, Some(24.5)), (50, Some(74.5))]);
+        let reply = query_reply(&mut ks, query(None, 1.5, None));
+        assert_eq!(reply.status, Status::TsBadRequest);
+    }
+
+    #[test]
+    fn test_dispatch_query_fill() {
+        let mut ks = Keyspace::new();
+        ks.create("test-ts".to_string(), Labels::new(), None, None)
+            .unwrap();
+        let req = request(
+            OpCode::OpTsMaddPoint,
+            TsMaddPoint {
+                points: vec![
+                    add_point("test-ts", 120, 1.0),
+                    add_point("test-ts", 420, 4.0),
+                ],
+            },
+        );
+        dispatch(&mut ks, &req);
+        let query = |range, interval, fill| TsQuery {
+            name: "test-ts".to_string(),
+            range,
+            aggregation: Some(Aggregator::Avg),
+            interval: Some(interval),
+            fill,
+            reverse: false,
+            offset: 0,
+            limit: None,
+            page_size: None,
+            cursor: None,
+            stream: false,
+        };
+        let reply = query_reply(&mut ks, query(Some((0, 500)), 100, FillPolicy::Linear));
+        assert_eq!(
+            reply.buckets,
+            vec![
+                (0, None),
+                (100, Some(1.0)),
+                (200, Some(2.0)),
+                (300, Some(3.0)),
+                (400, Some(4.0)),
+                (500, None)
+            ]
+        );
+        // Without a range the buckets span from the first to the last point
+        let reply = query_reply(&mut ks, query(None, 100, FillPolicy::Null));
+        assert_eq!(reply.buckets.len(), 4);
+        let reply = query_reply(&mut ks, query(Some((0, u128::MAX)), 1, FillPolicy::Null));
+        assert_eq!(reply.status, Status::TsBadRequest);
+    }
+
+    fn records_query(reverse: bool, limit: Option<usize>, cursor: Option<Vec<u8>>) -> TsQuery {
+        return TsQuery {
+            name: "test-ts".to_string(),
+            range: None,
+            aggregation: None,
+            interval: None,
+            fill: FillPolicy::None,
+            reverse,
+            offset: 0,
+            limit,
+            page_size: Some(2),
+            cursor,
+            stream: false,
+        };
+    }
+
+    #[test]
+    fn test_dispatch_query_pages() {
+        let mut ks = Keyspace::new();
+        ks.create("test-ts".to_string(), Labels::new(), None, None)
+            .unwrap();
+        // Points sharing a timestamp may be split across pages
+        let timestamps = [10, 20, 20, 20, 30, 40];
+        let points = timestamps
+            .iter()
+            .enumerate()
+            .map(|(i, t)| add_point("test-ts", *t, i as f64))
+            .collect();
+        dispatch(
+            &mut ks,
+            &request(OpCode::OpTsMaddPoint, TsMaddPoint { points }),
+        );
+        let pages = |ks: &mut Keyspace, reverse, limit| {
+            let mut values = Vec::new();
+            let mut cursor = None;
+            loop {
+                let reply = query_reply(ks, records_query(reverse, limit, cursor));
+                assert!(reply.records.len() <= 2);
+                values.extend(reply.records.iter().map(|r| r.value()));
+                cursor = reply.cursor;
+                if cursor.is_none() {
+                    return values;
+                }
+            }
+        };
+        assert_eq!(
+            pages(&mut ks, false, None),
+            vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0]
+        );
+        assert_eq!(
+            pages(&mut ks, true, None),
+            vec![5.0, 4.0, 3.0, 2.0, 1.0, 0.0]
+        );
+        assert_eq!(pages(&mut ks, false, Some(3)), vec![0.0, 1.0, 2.0]);
+        let first = query_reply(&mut ks, records_query(false, None, None));
+        let reply = query_reply(&mut ks, records_query(true, None, first.cursor));
+        assert_eq!(reply.status, Status::TsBadRequest);
+        let reply = query_reply(&mut ks, records_query(false, None, Some(vec![1, 2])));
+        assert_eq!(reply.status, Status::TsBadRequest);
+    }
+
+    #[test]
+    fn test_dispatch_query_stream() {
+        let mut ks = Keyspace::new();
+        ks.create("test-ts".to_string(), Labels::new(), None, None)
+            .unwrap();
+        let points = (0..5)
+            .map(|i| add_point("test-ts", i * 10, i as f64))
+            .collect();
+        dispatch(
+            &mut ks,
+            &request(OpCode::OpTsMaddPoint, TsMaddPoint { points }),
+        );
+        let mut query = records_query(false, None, None);
+        query.stream = true;
+        let req = request(OpCode::OpTsQuery, query);
+        let mut stream = super::stream(&req).unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = stream.next_frame(&ks) {
+            let reply: TsQueryReply = TsPacket::from_binary(&frame).unwrap().into_packet();
+            frames.push((reply.status, reply.records.len()));
+        }
+        assert_eq!(
+            frames,
+            vec![
+                (Status::TsOk, 2),
+                (Status::TsOk, 2),
+                (Status::TsOk, 1),
+                (Status::TsEndOfStream, 0)
+            ]
+        );
+        // Dispatched at once the frames of the stream are just concatenated
+        let response = dispatch(&mut ks, &req);
+        let mut offset = 0;
+        let mut count = 0;
+        while let Some(len) = protocol::frame_len(&response[offset..]) {
+            offset += len;
+            count += 1;
+        }
+        assert_eq!((offset, count), (response.len(), 4));
+        assert!(super::stream(&request(
+            OpCode::OpTsQuery,
+            records_query(false, None, None)
+        ))
+        .is_none());
+    }
+
+    #[test]
+    fn test_dispatch_info() {
+        let mut ks = Keyspace::new();
+        create_labelled(&mut ks, "cpu", &[("host", "web-1")]);
+        let req = request(
+            OpCode::OpTsMaddPoint,
+            TsMaddPoint {
+                points: vec![add_point("cpu", 10, 12.0), add_point("cpu", 20, 18.0)],
+            },
+        );
+        dispatch(&mut ks, &req);
+        let info = |ks: &mut Keyspace, name: &str| -> TsInfoReply {
+            let req = request(
+                OpCode::OpTsInfo,
+                TsInfo {
+                    name: name.to_string(),
+                },
+            );
+            return TsPacket::from_binary(&dispatch(ks, &req))
+                .unwrap()
+                .into_packet();
+        };
+        let reply = info(&mut ks, "cpu");
+        assert_eq!(reply.status, Status::TsOk);
+        assert_eq!(reply.labels["host"], "web-1");
+        assert_eq!(reply.stats.count, 2);
+        assert_eq!(reply.stats.mean(), Some(15.0));
+        assert_eq!(reply.stats.min, Some(12.0));
+        assert_eq!(reply.stats.latest, Some(Record::with_timestamp(20, 18.0)));
+        assert_eq!(info(&mut ks, "mem").status, Status::TsNotFount);
+    }
+
+    #[test]
+    fn test_dispatch_rate() {
+        let mut ks = Keyspace::new();
+        ks.create("requests".to_string(), Labels::new(), None, None)
+            .unwrap();
+        let req = request(
+            OpCode::OpTsMaddPoint,
+            TsMaddPoint {
+                points: vec![
+                    add_point("requests", 0, 100.0),
+                    add_point("requests", 1000, 300.0),
+                    add_point("requests", 2000, 50.0),
+                    add_point("requests", 3000, 150.0),
+                ],
+            },
+        );
+        dispatch(&mut ks, &req);
+        let rate = |range, interval| TsRate {
+            name: "requests".to_string(),
+            range,
+            function: RateFunction::Rate,
+            interval,
+            fill: FillPolicy::None,
+        };
+        let response = dispatch(&mut ks, &request(OpCode::OpTsRate, rate(None, None)));
+        let reply: TsQueryReply = TsPacket::from_binary(&response).unwrap().into_packet();
+        assert_eq!(reply.values, vec![350.0 / 3.0]);
+        let response = dispatch(
+            &mut ks,
+            &request(OpCode::OpTsRate, rate(Some((1000, 3000)), Some(2000))),
+        );
+        let reply: TsQueryReply = TsPacket::from_binary(&response).unwrap().into_packet();
+        assert_eq!(reply.buckets, vec![(2000, Some(75.0))]);
+    }
+
+    #[test]
+    fn test_dispatch_duplicate() {
+        let mut ks = Keyspace::new();
+        ks.create(
+            "test-ts".to_string(),
+            Labels::new(),
+            None,
+            Some(DuplicatePolicy::Block),
+        )
+        .unwrap();
+        let req = request(OpCode::OpTsAddPoint, add_point("test-ts", 10, 12.98));
+        assert_eq!(ack_of(&dispatch(&mut ks, &req)), TsAck::ok());
+        assert_eq!(ack_of(&dispatch(&mut ks, &req)).status, Status::TsDuplicate);
+        // Duplicates within the same batch reject it as a whole
+        let req = request(
+            OpCode::OpTsMaddPoint,
+            TsMaddPoint {
+                points: vec![
+                    add_point("test-ts", 20, 19.63),
+                    add_point("test-ts", 20, 11.28),
+                ],
+            },
+        );
+        assert_eq!(ack_of(&dispatch(&mut ks, &req)).status, Status::TsDuplicate);
+        assert_eq!(ks.get("test-ts").unwrap().len(), 1); // Points without a timestamp are never duplicates of each other
+        let points = (0..3)
+            .map(|i| TsAddPoint {
+                name: "test-ts".to_string(),
+                timestamp: None,
+                value: i as f64,
+            })
+            .collect();
+        let req = request(OpCode::OpTsMaddPoint, TsMaddPoint { points });
+        assert_eq!(ack_of(&dispatch(&mut ks, &req)), TsAck::ok());
+        let timestamps: Vec<u128> = ks
+            .get("test-ts")
+            .unwrap()
+            .iter()
+            .map(|r| r.timestamp())
+            .collect();
+        assert_eq!(timestamps.len(), 4);
+        assert!(timestamps.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn test_dispatch_not_found() {
+        let mut ks = Keyspace::new();
+        let req = request(
+            OpCode::OpTsDelete,
+            TsDelete {
+                name: "test-ts".to_string(),
+            },
+        );
+        assert_eq!(ack_of(&dispatch(&mut ks, &req)).status, Status::TsNotFount);
+    }
+
+    #[test]
+    fn test_dispatch_malformed_payload() {
+        let mut ks = Keyspace::new();
+        let mut req = request(
+            OpCode::OpTsDelete,
+            TsDelete {
+                name: "test-ts".to_string(),
+            },
+        );
+        // Claim a string longer than the whole payload
+        req[crate::protocol::HEADER_SIZE] = 0xff;
+        assert_eq!(
+            ack_of(&dispatch(&mut ks, &req)).status,
+            Status::TsBadRequest
+        );
+    }
+
+    fn create_labelled(ks: &mut Keyspace, name: &str, labels: &[(&str, &str)]) -> TsAck {
+        let req = request(
+            OpCode::OpTsCreate,
+            TsCreate {
+                name: name.to_string(),
+                labels: labels
+                    .iter()
+                    .map(|(l, v)| (l.to_string(), v.to_string()))
+                    .collect(),
+                retention: None,
+                duplicate_policy: None,
+            },
+        );
+        return ack_of(&dispatch(ks, &req));
+    }
+
+    fn select_reply(ks: &mut Keyspace, select: TsSelect) -> TsSelectReply {
+        let response = dispatch(ks, &request(OpCode::OpTsSelect, select));
+        let reply: TsPacket<TsSelectReply> = TsPacket::from_binary(&response).unwrap();
+        return reply.into_packet();
+    }
+
+    #[test]
+    fn test_dispatch_select() {
+        let mut ks = Keyspace::new();
+        create_labelled(&mut ks, "cpu", &[("host", "web-1")]);
+        create_labelled(&mut ks, "cpu-db", &[("host", "db-1")]);
+        create_labelled(&mut ks, "mem", &[("host", "web-1")]);
+        let req = request(
+            OpCode::OpTsMaddPoint,
+            TsMaddPoint {
+                points: vec![add_point("cpu", 10, 12.98), add_point("cpu", 20, 19.63)],
+            },
+        );
+        dispatch(&mut ks, &req);
+        let reply = select_reply(
+            &mut ks,
+            TsSelect {
+                matchers: vec![
+                    Matcher::new(NAME_LABEL, MatchOp::Re, "cpu.*"),
+                    Matcher::new("host", MatchOp::Re, "web-.*"),
+                ],
+                range: Some((15, 30)),
+            },
+        );
+        assert_eq!(reply.status, Status::TsOk);
+        assert_eq!(reply.series.len(), 1);
+        assert_eq!(reply.series[0].name, "cpu");
+        assert_eq!(reply.series[0].labels["host"], "web-1");
+        assert_eq!(reply.series[0].records.len(), 1);
+        let reply = select_reply(
+            &mut ks,
+            TsSelect {
+                matchers: vec![Matcher::new("host", MatchOp::Re, "web-(")],
+                range: None,
+            },
+        );
+        assert_eq!(reply.status, Status::TsBadRequest);
+        let reply = select_reply(
+            &mut ks,
+            TsSelect {
+                matchers: Vec::new(),
+                range: None,
+            },
+        );
+        assert_eq!(reply.status, Status::TsBadRequest);
+    }
+
+    #[test]
+    fn test_dispatch_aggregate() {
+        let mut ks = Keyspace::new();
+        create_labelled(&mut ks, "cpu-1", &[("region", "eu"), ("host", "web-1")]);
+        create_labelled(&mut ks, "cpu-2", &[("region", "eu"), ("host", "web-1")]);
+        create_labelled(&mut ks, "cpu-3", &[("region", "eu"), ("host", "web-2")]);
+        create_labelled(&mut ks, "cpu-4", &[("region", "us"), ("host", "web-1")]);
+        let req = request(
+            OpCode::OpTsMaddPoint,
+            TsMaddPoint {
+                points: vec![
+                    add_point("cpu-1", 10, 1.0),
+                    add_point("cpu-1", 110, 2.0),
+                    add_point("cpu-2", 20, 3.0),
+                    add_point("cpu-3", 30, 5.0),
+                    add_point("cpu-4", 40, 7.0),
+                ],
+            },
+        );
+        dispatch(&mut ks, &req);
+        let response = dispatch(
+            &mut ks,
+            &request(
+                OpCode::OpTsAggregate,
+                TsAggregate {
+                    matchers: vec![Matcher::new("region", MatchOp::Eq, "eu")],
+                    range: None,
+                    aggregation: Aggregator::Avg,
+                    interval: 100,
+                    group_by: vec!["host".to_string()],
+                    fill: FillPolicy::None,
+                },
+            ),
+        );
+        let reply: TsAggregateReply = TsPacket::from_binary(&response).unwrap().into_packet();
+        assert_eq!(reply.status, Status::TsOk);
+        assert_eq!(reply.groups.len(), 2);
+        assert_eq!(reply.groups[0].labels["host"], "web-1");
+        assert_eq!(
+            reply.groups[0].points,
+            vec![(0, Some(2.0)), (100, Some(2.0))]
+        );
+        assert_eq!(reply.groups[1].labels["host"], "web-2");
+        assert_eq!(reply.groups[1].points, vec![(0, Some(5.0))]);
+    }
+
+    #[test]
+    fn test_dispatch_create_bad_labels() {
+        let mut ks = Keyspace::new();
+        let ack = create_labelled(&mut ks, "cpu", &[(NAME_LABEL, "mem")]);
+        assert_eq!(ack.status, Status::TsBadRequest);
+        let ack = create_labelled(&mut ks, "cpu", &[("host", "")]);
+        assert_eq!(ack.status, Status::TsBadRequest);
+        assert!(ks.get("cpu").is_none());
+    }
+
+    #[test]
+    fn test_dispatch_unknown_command() {
+        let mut ks = Keyspace::new();
+        let mut req = request(
+            OpCode::OpTsDelete,
+            TsDelete {
+                name: "test-ts".to_string(),
+            },
+        );
+        req[0] = 0xf0;
+        assert_eq!(
+            ack_of(&dispatch(&mut ks, &req)).status,
+            Status::TsUnknownCmd
+        );
+        // Clients can't send error frames
+        req[0] = (OpCode::OpTsError as u8) << 4;
+        assert_eq!(
+            ack_of(&dispatch(&mut ks, &req)).status,
+            Status::TsUnknownCmd
+        );
+    }
+
+    #[test]
+    fn test_error_frame() {
+        let frame = error_frame(Status::TsLimitExceeded, "Too large".to_string());
+        let header = TsHeader::from_binary(&frame).unwrap();
+        assert_eq!(header.opcode(), Some(OpCode::OpTsError));
+        assert_eq!(
+            ack_of(&frame),
+            TsAck::error(Status::TsLimitExceeded, "Too large".to_string())
+        );
+    }
+}
@@ -0,0 +1,255 @@
+// BSD 2-Clause License
+//
+// Copyright (c) 2020, Andrea Giacomo Baldan
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+//   list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+//   this list of conditions and the following disclaimer in the documentation
+//   and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+// Pseudo label carrying the name of every series, so series can be selected by name like any
+// other label
+pub const NAME_LABEL: &str = "__name__";
+
+// Key/value pairs attached to a series at creation, kept sorted to have a stable representation
+pub type Labels = BTreeMap<String, String>;
+
+// How a matcher compares the value of a label: equal, not equal, matching or not matching a
+// regular expression. Regular expressions are anchored, they must match the whole value.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum MatchOp {
+    Eq,
+    Neq,
+    Re,
+    Nre,
+}
+
+// A condition on the value of a label, a series lacking the label is treated as having it with an
+// empty value, so `label=""` selects the series without it
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Matcher {
+    pub label: String,
+    pub op: MatchOp,
+    pub value: String,
+}
+
+impl Matcher {
+    // Matchers are only ever built on the client side, or by the tests
+    #[cfg(test)]
+    pub fn new(label: &str, op: MatchOp, value: &str) -> Matcher {
+        Matcher {
+            label: label.to_string(),
+            op,
+            value: value.to_string(),
+        }
+    }
+}
+
+// Inverted index from every label pair to the names of the series carrying it
+#[derive(Default)]
+pub struct LabelIndex {
+    postings: HashMap<String, HashMap<String, HashSet<String>>>,
+    series: HashSet<String>,
+}
+
+impl LabelIndex {
+    pub fn new() -> LabelIndex {
+        LabelIndex {
+            postings: HashMap::new(),
+            series: HashSet::new(),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, labels: &Labels) {
+        self.series.insert(name.to_string());
+        for (label, value) in pairs(name, labels) {
+            self.postings
+                .entry(label.to_string())
+                .or_default()
+                .entry(value.to_string())
+                .or_default()
+                .insert(name.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, name: &str, labels: &Labels) {
+        self.series.remove(name);
+        for (label, value) in pairs(name, labels) {
+            let values = match self.postings.get_mut(label) {
+                Some(v) => v,
+                None => continue,
+            };
+            if let Some(names) = values.get_mut(value) {
+                names.remove(name);
+                if names.is_empty() {
+                    values.remove(value);
+                }
+            }
+            if values.is_empty() {
+                self.postings.remove(label);
+            }
+        }
+    }
+
+    // Names of the series satisfying every matcher, sorted. Fails if a regular expression is not
+    // valid.
+    pub fn select(&self, matchers: &[Matcher]) -> Result<BTreeSet<String>, regex::Error> {
+        let mut selected: Option<HashSet<String>> = None;
+        for m in matchers {
+            let matching = match m.op {
+                MatchOp::Eq => self.matching(&m.label, |v| v == m.value),
+                MatchOp::Neq => self.complement(self.matching(&m.label, |v| v == m.value)),
+                MatchOp::Re => {
+                    let re = anchored(&m.value)?;
+                    self.matching(&m.label, |v| re.is_match(v))
+                }
+                MatchOp::Nre => {
+                    let re = anchored(&m.value)?;
+                    self.complement(self.matching(&m.label, |v| re.is_match(v)))
+                }
+            };
+            selected = match selected {
+                Some(s) => Some(s.intersection(&matching).cloned().collect()),
+                None => Some(matching),
+            };
+        }
+        return Ok(selected.unwrap_or_default().into_iter().collect());
+    }
+
+    // Series with a value of the label accepted by the predicate, plus the ones without the label
+    // if the predicate accepts the empty value
+    fn matching<F>(&self, label: &str, accepts: F) -> HashSet<String>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut matching = HashSet::new();
+        let values = self.postings.get(label);
+        if let Some(values) = values {
+            for (value, names) in values {
+                if accepts(value) {
+                    matching.extend(names.iter().cloned());
+                }
+            }
+        }
+        if accepts("") {
+            let labelled: HashSet<&String> = values
+                .map(|v| v.values().flatten().collect())
+                .unwrap_or_default();
+            matching.extend(
+                self.series
+                    .iter()
+                    .filter(|n| !labelled.contains(n))
+                    .cloned(),
+            );
+        }
+        return matching;
+    }
+
+    fn complement(&self, set: HashSet<String>) -> HashSet<String> {
+        return self.series.difference(&set).cloned().collect();
+    }
+}
+
+fn pairs<'a>(name: &'a str, labels: &'a Labels) -> impl Iterator<Item = (&'a str, &'a str)> {
+    return labels
+        .iter()
+        .map(|(l, v)| (l.as_str(), v.as_str()))
+        .chain(std::iter::once((NAME_LABEL, name)));
+}
+
+fn anchored(pattern: &str) -> Result<Regex, regex::Error> {
+    return Regex::new(&format!("^(?:{})$", pattern));
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        return pairs
+            .iter()
+            .map(|(l, v)| (l.to_string(), v.to_string()))
+            .collect();
+    }
+
+    fn index() -> LabelIndex {
+        let mut index = LabelIndex::new();
+        index.add("cpu-1", &labels(&[("host", "web-1"), ("dc", "eu")]));
+        index.add("cpu-2", &labels(&[("host", "web-2"), ("dc", "us")]));
+        index.add("cpu-3", &labels(&[("host", "db-1"), ("dc", "eu")]));
+        index.add("mem-1", &labels(&[("host", "web-1")]));
+        return index;
+    }
+
+    fn select(index: &LabelIndex, matchers: &[Matcher]) -> Vec<String> {
+        return index.select(matchers).unwrap().into_iter().collect();
+    }
+
+    #[test]
+    fn test_index_select_eq() {
+        let index = index();
+        let m = [Matcher::new("dc", MatchOp::Eq, "eu")];
+        assert_eq!(select(&index, &m), vec!["cpu-1", "cpu-3"]);
+        let m = [Matcher::new(NAME_LABEL, MatchOp::Eq, "mem-1")];
+        assert_eq!(select(&index, &m), vec!["mem-1"]);
+        // Series without the label have it empty
+        let m = [Matcher::new("dc", MatchOp::Eq, "")];
+        assert_eq!(select(&index, &m), vec!["mem-1"]);
+    }
+
+    #[test]
+    fn test_index_select_neq() {
+        let index = index();
+        let m = [Matcher::new("dc", MatchOp::Neq, "eu")];
+        assert_eq!(select(&index, &m), vec!["cpu-2", "mem-1"]);
+    }
+
+    #[test]
+    fn test_index_select_regex() {
+        let index = index();
+        let m = [
+            Matcher::new(NAME_LABEL, MatchOp::Re, "cpu-.*"),
+            Matcher::new("host", MatchOp::Re, "web-.*"),
+        ];
+        assert_eq!(select(&index, &m), vec!["cpu-1", "cpu-2"]);
+        // Regular expressions must match the whole value
+        let m = [Matcher::new("host", MatchOp::Re, "web")];
+        assert!(select(&index, &m).is_empty());
+        let m = [Matcher::new("host", MatchOp::Nre, "web-.*")];
+        assert_eq!(select(&index, &m), vec!["cpu-3"]);
+        let m = [Matcher::new("host", MatchOp::Re, "(")];
+        assert!(index.select(&m).is_err());
+    }
+
+    #[test]
+    fn test_index_remove() {
+        let mut index = index();
+        index.remove("cpu-1", &labels(&[("host", "web-1"), ("dc", "eu")]));
+        let m = [Matcher::new("host", MatchOp::Eq, "web-1")];
+        assert_eq!(select(&index, &m), vec!["mem-1"]);
+        let m = [Matcher::new("dc", MatchOp::Neq, "us")];
+        assert_eq!(select(&index, &m), vec!["cpu-3", "mem-1"]);
+    }
+}
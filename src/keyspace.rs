@@ -0,0 +1,423 @@
+// BSD 2-Clause License
+//
+// Copyright (c) 2020, Andrea Giacomo Baldan
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+//   list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+//   this list of conditions and the following disclaimer in the documentation
+//   and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::index::{LabelIndex, Labels, Matcher};
+use crate::snapshot;
+use crate::timeseries::{DuplicatePolicy, Record, Retention, TimeSeries};
+use crate::wal::{FsyncPolicy, Wal, WalEntry};
+use log::warn;
+use std::collections::HashMap;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+
+const WAL_FILE: &str = "teaspoon.wal";
+const SNAPSHOT_FILE: &str = "teaspoon.snapshot";
+
+// The keyspace is the set of all timeseries living in the server memory, each one indexed by its
+// name, which must be unique, and by its labels to select series matching some conditions. Every
+// change is first logged to the write-ahead log, if any, so the keyspace can be rebuilt on restart
+// from the latest snapshot plus the changes logged after it. The lsn is the sequence number of the
+// last change applied.
+#[derive(Default)]
+pub struct Keyspace {
+    series: HashMap<String, TimeSeries>,
+    index: LabelIndex,
+    wal: Option<Wal>,
+    snapshot_path: Option<PathBuf>,
+    lsn: u64,
+    default_retention: Option<Retention>,
+}
+
+impl Keyspace {
+    pub fn new() -> Keyspace {
+        Keyspace {
+            series: HashMap::new(),
+            index: LabelIndex::new(),
+            wal: None,
+            snapshot_path: None,
+            lsn: 0,
+            default_retention: None,
+        }
+    }
+
+    // Rebuild the keyspace persisted in the data directory, loading the latest snapshot and
+    // replaying the write-ahead log entries not yet part of it. The log is then kept open to log
+    // every new change.
+    pub fn open(dir: &Path, fsync: FsyncPolicy) -> Result<Keyspace, Error> {
+        let mut keyspace = Keyspace::new();
+        let snapshot_path = dir.join(SNAPSHOT_FILE);
+        if let Some(snapshot) = snapshot::load(&snapshot_path)? {
+            keyspace.lsn = snapshot.lsn;
+            for ts in snapshot.series {
+                keyspace.index.add(ts.name(), ts.labels());
+                keyspace.series.insert(ts.name().to_string(), ts);
+            }
+        }
+        let wal_path = dir.join(WAL_FILE);
+        for (lsn, entry) in Wal::replay(&wal_path)? {
+            // A crash right after a snapshot may leave entries already part of it in the log
+            if lsn > keyspace.lsn {
+                keyspace.apply(entry);
+                keyspace.lsn = lsn;
+            }
+        }
+        keyspace.wal = Some(Wal::open(&wal_path, fsync)?);
+        keyspace.snapshot_path = Some(snapshot_path);
+        return Ok(keyspace);
+    }
+
+    // Whether the data directory holds a persisted keyspace
+    pub fn exists(dir: &Path) -> bool {
+        return dir.join(SNAPSHOT_FILE).exists() || dir.join(WAL_FILE).exists();
+    }
+
+    // Retention of the series created without one
+    pub fn set_default_retention(&mut self, retention: Option<Retention>) {
+        self.default_retention = retention;
+    }
+
+    // Add a new empty timeseries to the keyspace, return false if a timeseries with the same name
+    // already exists, leaving it untouched
+    pub fn create(
+        &mut self,
+        name: String,
+        labels: Labels,
+        retention: Option<Retention>,
+        duplicate_policy: Option<DuplicatePolicy>,
+    ) -> Result<bool, Error> {
+        if self.series.contains_key(&name) {
+            return Ok(false);
+        }
+        // The default is resolved here so replaying the log doesn't depend on the configuration
+        let retention = retention.or(self.default_retention);
+        self.log(WalEntry::Create {
+            name,
+            labels,
+            retention,
+            duplicate_policy,
+        })?;
+        return Ok(true);
+    }
+
+    // Remove a timeseries from the keyspace, return false if it doesn't exist
+    pub fn delete(&mut self, name: &str) -> Result<bool, Error> {
+        if !self.series.contains_key(name) {
+            return Ok(false);
+        }
+        self.log(WalEntry::Delete {
+            name: name.to_string(),
+        })?;
+        return Ok(true);
+    }
+
+    // Add a batch of points, each one to the timeseries it's paired with. Points for unknown
+    // series are ignored, validation is up to the caller.
+    pub fn add_points(&mut self, points: Vec<(String, Record)>) -> Result<(), Error> {
+        return self.log(WalEntry::AddPoints { points });
+    }
+
+    // Flush the write-ahead log according to its fsync policy, meant to be called periodically
+    pub fn tick(&mut self) -> Result<(), Error> {
+        match self.wal.as_mut() {
+            Some(wal) => return wal.tick(),
+            None => return Ok(()),
+        }
+    }
+
+    // Save a snapshot of the whole keyspace, after which the write-ahead log can be emptied
+    pub fn snapshot(&mut self) -> Result<(), Error> {
+        let path = match self.snapshot_path.as_ref() {
+            Some(p) => p,
+            None => return Err(Error::other("Persistence is disabled")),
+        };
+        snapshot::save(path, self.lsn, self.series.values().collect())?;
+        // The snapshot is durable once saved, directory included, the log can be truncated
+        if let Some(wal) = self.wal.as_mut() {
+            wal.truncate()?;
+        }
+        return Ok(());
+    }
+
+    // Persist every change before shutting down, saving a snapshot so that the next start doesn't
+    // have to replay the whole log. Failing that, the log synced to the disk is enough to rebuild
+    // the keyspace.
+    pub fn close(&mut self) -> Result<(), Error> {
+        if self.snapshot_path.is_none() {
+            return Ok(());
+        }
+        if let Err(e) = self.snapshot() {
+            warn!("Cannot save snapshot: {}", e);
+            if let Some(wal) = self.wal.as_mut() {
+                return wal.sync();
+            }
+        }
+        return Ok(());
+    }
+
+    fn log(&mut self, entry: WalEntry) -> Result<(), Error> {
+        if let Some(wal) = self.wal.as_mut() {
+            wal.append(self.lsn + 1, &entry)?;
+        }
+        self.lsn += 1;
+        self.apply(entry);
+        return Ok(());
+    }
+
+    fn apply(&mut self, entry: WalEntry) {
+        match entry {
+            WalEntry::Create {
+                name,
+                labels,
+                retention,
+                duplicate_policy,
+            } => {
+                self.index.add(&name, &labels);
+                let ts = TimeSeries::with_labels(name.clone(), labels, retention, duplicate_policy);
+                self.series.insert(name, ts);
+            }
+            WalEntry::Delete { name } => {
+                if let Some(ts) = self.series.remove(&name) {
+                    self.index.remove(&name, ts.labels());
+                }
+            }
+            WalEntry::AddPoints { points } => {
+                for (name, record) in points {
+                    if let Some(ts) = self.series.get_mut(&name) {
+                        ts.add_point(record);
+                    }
+                }
+            }
+        }
+    }
+
+    // Drop the points expired according to the retention policy of each timeseries idle since the
+    // previous sweeps, now being the wall clock time
+    pub fn expire(&mut self, now: u128) {
+        for ts in self.series.values_mut() {
+            ts.expire(now);
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TimeSeries> {
+        return self.series.get(name);
+    }
+
+    // Every timeseries satisfying all the matchers, sorted by name
+    pub fn select(&self, matchers: &[Matcher]) -> Result<Vec<&TimeSeries>, regex::Error> {
+        let names = self.index.select(matchers)?;
+        return Ok(names.iter().filter_map(|n| self.series.get(n)).collect());
+    }
+
+    // Log every change to a device that's always full, as if the disk of the log were
+    #[cfg(test)]
+    pub fn break_wal(&mut self) {
+        self.wal = Some(Wal::open(Path::new("/dev/full"), FsyncPolicy::Never).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::index::{MatchOp, NAME_LABEL};
+
+    #[test]
+    fn test_keyspace_create() {
+        let mut ks = Keyspace::new();
+        assert!(ks
+            .create("test-ts".to_string(), Labels::new(), None, None)
+            .unwrap());
+        assert!(!ks
+            .create("test-ts".to_string(), Labels::new(), None, None)
+            .unwrap());
+        assert!(ks.get("test-ts").is_some());
+        ks.set_default_retention(Some(Retention::Count(10)));
+        ks.create("ts-1".to_string(), Labels::new(), None, None)
+            .unwrap();
+        ks.create(
+            "ts-2".to_string(),
+            Labels::new(),
+            Some(Retention::Age(5)),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            ks.get("ts-1").unwrap().retention(),
+            Some(Retention::Count(10))
+        );
+        assert_eq!(ks.get("ts-2").unwrap().retention(), Some(Retention::Age(5)));
+    }
+
+    #[test]
+    fn test_keyspace_delete() {
+        let mut ks = Keyspace::new();
+        ks.create("test-ts".to_string(), Labels::new(), None, None)
+            .unwrap();
+        assert!(ks.delete("test-ts").unwrap());
+        assert!(!ks.delete("test-ts").unwrap());
+        assert!(ks.get("test-ts").is_none());
+    }
+
+    #[test]
+    fn test_keyspace_expire() {
+        let mut ks = Keyspace::new();
+        ks.create(
+            "ts-1".to_string(),
+            Labels::new(),
+            Some(Retention::Age(100)),
+            None,
+        )
+        .unwrap();
+        ks.create("ts-2".to_string(), Labels::new(), None, None)
+            .unwrap();
+        for name in &["ts-1", "ts-2"] {
+            let points = vec![
+                (name.to_string(), Record::with_timestamp(10, 12.98)),
+                (name.to_string(), Record::with_timestamp(90, 19.63)),
+            ];
+            ks.add_points(points).unwrap();
+        }
+        ks.expire(1000);
+        assert_eq!(ks.get("ts-1").unwrap().len(), 2);
+        ks.expire(1060);
+        assert_eq!(ks.get("ts-1").unwrap().len(), 1);
+        assert_eq!(ks.get("ts-2").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_keyspace_select() {
+        let dir = data_dir("keyspace-select");
+        let mut ks = Keyspace::open(&dir, FsyncPolicy::Never).unwrap();
+        for (name, host) in &[("cpu-1", "web-1"), ("cpu-2", "db-1"), ("mem-1", "web-1")] {
+            let mut labels = Labels::new();
+            labels.insert("host".to_string(), host.to_string());
+            ks.create(name.to_string(), labels, None, None).unwrap();
+        }
+        let matchers = [
+            Matcher::new(NAME_LABEL, MatchOp::Re, "cpu-.*"),
+            Matcher::new("host", MatchOp::Re, "web-.*"),
+        ];
+        let names = |ks: &Keyspace| -> Vec<String> {
+            let selected = ks.select(&matchers).unwrap();
+            return selected.iter().map(|ts| ts.name().to_string()).collect();
+        };
+        assert_eq!(names(&ks), vec!["cpu-1"]);
+        ks.snapshot().unwrap();
+        ks.delete("cpu-1").unwrap();
+        assert!(names(&ks).is_empty());
+        ks.create("cpu-3".to_string(), Labels::new(), None, None)
+            .unwrap();
+        // The index is rebuilt from both the snapshot and the log
+        let ks = Keyspace::open(&dir, FsyncPolicy::Never).unwrap();
+        assert!(names(&ks).is_empty());
+        let matchers = [Matcher::new("host", MatchOp::Eq, "")];
+        let selected = ks.select(&matchers).unwrap();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].name(), "cpu-3");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn data_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("teaspoon-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        return dir;
+    }
+
+    #[test]
+    fn test_keyspace_open() {
+        let dir = data_dir("keyspace-open");
+        assert!(!Keyspace::exists(&dir));
+        let mut ks = Keyspace::open(&dir, FsyncPolicy::Always).unwrap();
+        ks.create("ts-1".to_string(), Labels::new(), None, None)
+            .unwrap();
+        ks.create("ts-2".to_string(), Labels::new(), None, None)
+            .unwrap();
+        let points = vec![
+            ("ts-1".to_string(), Record::with_timestamp(10, 12.98)),
+            ("ts-2".to_string(), Record::with_timestamp(10, 19.63)),
+        ];
+        ks.add_points(points).unwrap();
+        ks.delete("ts-2").unwrap();
+        assert!(Keyspace::exists(&dir));
+        let ks = Keyspace::open(&dir, FsyncPolicy::Always).unwrap();
+        assert_eq!(ks.get("ts-1").unwrap().len(), 1);
+        assert!(ks.get("ts-2").is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_keyspace_snapshot() {
+        let dir = data_dir("keyspace-snapshot");
+        let mut ks = Keyspace::open(&dir, FsyncPolicy::Never).unwrap();
+        ks.create("ts-1".to_string(), Labels::new(), None, None)
+            .unwrap();
+        let points = vec![("ts-1".to_string(), Record::with_timestamp(10, 12.98))];
+        ks.add_points(points).unwrap();
+        ks.snapshot().unwrap();
+        assert!(Wal::replay(&dir.join(WAL_FILE)).unwrap().is_empty());
+        let points = vec![("ts-1".to_string(), Record::with_timestamp(20, 19.63))];
+        ks.add_points(points).unwrap();
+        let ks = Keyspace::open(&dir, FsyncPolicy::Never).unwrap();
+        assert_eq!(ks.lsn, 3);
+        assert_eq!(ks.get("ts-1").unwrap().len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_keyspace_close() {
+        assert!(Keyspace::new().close().is_ok());
+        let dir = data_dir("keyspace-close");
+        let mut ks = Keyspace::open(&dir, FsyncPolicy::EverySecond).unwrap();
+        ks.create("ts-1".to_string(), Labels::new(), None, None)
+            .unwrap();
+        ks.close().unwrap();
+        assert!(Wal::replay(&dir.join(WAL_FILE)).unwrap().is_empty());
+        let ks = Keyspace::open(&dir, FsyncPolicy::Never).unwrap();
+        assert!(ks.get("ts-1").is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_keyspace_snapshot_stale_wal() {
+        let dir = data_dir("keyspace-stale");
+        let mut ks = Keyspace::open(&dir, FsyncPolicy::Never).unwrap();
+        ks.create("ts-1".to_string(), Labels::new(), None, None)
+            .unwrap();
+        let points = vec![("ts-1".to_string(), Record::with_timestamp(10, 12.98))];
+        ks.add_points(points).unwrap();
+        // Crash between the snapshot and the log truncation, entries are not applied twice
+        snapshot::save(
+            &dir.join(SNAPSHOT_FILE),
+            ks.lsn,
+            ks.series.values().collect(),
+        )
+        .unwrap();
+        let ks = Keyspace::open(&dir, FsyncPolicy::Never).unwrap();
+        assert_eq!(ks.get("ts-1").unwrap().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -24,18 +24,66 @@
 // OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+// Explicit returns are the preferred style across the codebase
+#![allow(clippy::needless_return)]
+
+mod aggregate;
+mod chunk;
+mod config;
+mod counter;
+mod dispatcher;
+mod index;
+mod keyspace;
 mod protocol;
 mod server;
+mod shard;
+mod sketch;
+mod snapshot;
+mod summary;
 mod timeseries;
+mod wal;
 
-const HOST: &str = "127.0.0.1";
-const PORT: i32 = 29191;
+use config::Config;
+use keyspace::Keyspace;
+use log::{error, warn};
+use std::env;
+use std::process;
 
 fn main() {
-    let mut server = server::Server::new(HOST.to_string(), PORT);
-    println!("Server starting on {}:{}", HOST, PORT);
-    let run = server.run();
-    if let Err(e) = run {
-        panic!("Cannot start the server: {}", e.to_string());
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        print!("{}", config::USAGE);
+        return;
+    }
+    let config = match Config::from_args(&args) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    env_logger::Builder::new()
+        .filter_level(config.log_level)
+        .init();
+    let mut keyspaces = match &config.data_dir {
+        Some(dir) => match shard::open(dir, config.workers, config.fsync) {
+            Ok(k) => k,
+            Err(e) => {
+                error!("Cannot load the keyspace from {}: {}", dir.display(), e);
+                process::exit(1);
+            }
+        },
+        None => {
+            warn!("No data directory set, the keyspace is not persisted");
+            (0..config.workers).map(|_| Keyspace::new()).collect()
+        }
+    };
+    for keyspace in keyspaces.iter_mut() {
+        keyspace.set_default_retention(config.retention);
+    }
+    let mut server = server::Server::new(&config, keyspaces);
+    if let Err(e) = server.run() {
+        error!("Cannot run the server: {}", e);
+        process::exit(1);
     }
 }
@@ -24,44 +24,75 @@
 // OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+use crate::counter::RateFunction;
+use crate::index::{Labels, Matcher};
+use crate::timeseries::{
+    Aggregator, DuplicatePolicy, FillPolicy, Record, Retention, Stats, TimeSeries,
+};
 use serde::{Deserialize, Serialize};
 use std::marker::PhantomData;
 
-#[derive(Debug, PartialEq)]
+// Size in bytes of a serialized header, a single byte for the opcode plus a u64 for the size of
+// the packet
+pub const HEADER_SIZE: usize = 9;
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum OpCode {
     OpTsCreate,
     OpTsDelete,
     OpTsAddPoint,
     OpTsMaddPoint,
     OpTsQuery,
+    OpTsSnapshot,
+    OpTsSelect,
+    OpTsAggregate,
+    OpTsRate,
+    OpTsInfo,
+    // Sent by the server only, an error right before it closes the connection
+    OpTsError,
 }
 
-enum Status {
+// Outcome of a command, sent back to the client as part of every response
+#[allow(clippy::enum_variant_names)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum Status {
     TsOk,
     TsNotFount,
     TsExists,
     TsUnknownCmd,
+    TsBadRequest,
+    TsDuplicate,
+    TsServerError,
+    TsEndOfStream,
+    TsLimitExceeded,
 }
 
 trait AsOpcode {
-    fn as_opcode(self) -> Option<OpCode>;
+    fn as_opcode(&self) -> Option<OpCode>;
 }
 
 impl AsOpcode for u8 {
-    fn as_opcode(self) -> Option<OpCode> {
+    fn as_opcode(&self) -> Option<OpCode> {
         match self {
             0 => Some(OpCode::OpTsCreate),
             1 => Some(OpCode::OpTsDelete),
             2 => Some(OpCode::OpTsAddPoint),
             3 => Some(OpCode::OpTsMaddPoint),
             4 => Some(OpCode::OpTsQuery),
+            5 => Some(OpCode::OpTsSnapshot),
+            6 => Some(OpCode::OpTsSelect),
+            7 => Some(OpCode::OpTsAggregate),
+            8 => Some(OpCode::OpTsRate),
+            9 => Some(OpCode::OpTsInfo),
+            10 => Some(OpCode::OpTsError),
             _ => None,
         }
     }
 }
 
 #[derive(Debug, PartialEq)]
-struct TsPacket<'a, T>
+pub struct TsPacket<'a, T>
 where
     T: Serialize,
     T: Deserialize<'a>,
@@ -72,26 +103,343 @@ where
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
-struct TsHeader {
+pub struct TsHeader {
     byte: u8,
     size: usize,
 }
 
 impl TsHeader {
+    pub fn new(opcode: OpCode, size: usize) -> TsHeader {
+        TsHeader {
+            byte: (opcode as u8) << 4,
+            size,
+        }
+    }
+
     pub fn opcode(&self) -> Option<OpCode> {
         return (self.byte >> 4).as_opcode();
     }
+
+    pub fn byte(&self) -> u8 {
+        return self.byte;
+    }
+
+    pub fn size(&self) -> usize {
+        return self.size;
+    }
+
+    pub fn from_binary(b: &[u8]) -> Result<TsHeader, Box<bincode::ErrorKind>> {
+        if b.len() < HEADER_SIZE {
+            return Err(Box::new(bincode::ErrorKind::Custom(
+                "Not enough bytes".to_string(),
+            )));
+        }
+        return bincode::deserialize(&b[..HEADER_SIZE]);
+    }
+}
+
+// Return the length of the first frame of the buffer, header included, if it has been completely
+// received, None if only a partial frame is available so far
+pub fn frame_len(b: &[u8]) -> Option<usize> {
+    let header = TsHeader::from_binary(b).ok()?;
+    let len = HEADER_SIZE.checked_add(header.size)?;
+    if b.len() < len {
+        return None;
+    }
+    return Some(len);
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
-struct TsCreate {
-    name: String,
-    retention: i32,
+pub struct TsCreate {
+    pub name: String,
+    pub labels: Labels,
+    pub retention: Option<Retention>,
+    pub duplicate_policy: Option<DuplicatePolicy>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct TsDelete {
+    pub name: String,
+}
+
+// A single point to be added to a series, if no timestamp is given the current time on the server
+// is used
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct TsAddPoint {
+    pub name: String,
+    pub timestamp: Option<u128>,
+    pub value: f64,
+}
+
+// A batch of points, possibly spanning multiple series, either entirely stored or rejected when
+// its series belong to the same shard. A batch spanning shards isn't atomic: each shard stores its
+// part once every part has been validated, yet a part can still be refused then, as another
+// client wrote to its series in between or its write-ahead log can't be written, while the other
+// parts are stored nonetheless. The error of the part refused is replied.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct TsMaddPoint {
+    pub points: Vec<TsAddPoint>,
+}
+
+// Query a series, optionally restricted to the [lo, hi] time range. Without an aggregation the
+// raw records are returned, newest first if reversed, skipping the first offset ones and up to
+// limit of them, otherwise a single aggregated value or one for each interval bucket, paired with
+// the bucket start. Empty buckets are filled according to the fill policy.
+//
+// Raw records can be split in pages of at most page_size records, each reply carries the cursor
+// to pass to fetch the next page, if any. Streamed queries are replied with a frame for each page
+// followed by an end-of-stream frame, without the need to request the following pages.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct TsQuery {
+    pub name: String,
+    pub range: Option<(u128, u128)>,
+    pub aggregation: Option<Aggregator>,
+    pub interval: Option<u128>,
+    pub fill: FillPolicy,
+    pub reverse: bool,
+    pub offset: usize,
+    pub limit: Option<usize>,
+    pub page_size: Option<usize>,
+    pub cursor: Option<Vec<u8>>,
+    pub stream: bool,
+}
+
+// Apply a rate function to a series, optionally restricted to the [lo, hi] time range, either to
+// the whole range or to each interval bucket. The reply is the same of a query, with a single
+// value or the buckets, empty ones filled according to the fill policy.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct TsRate {
+    pub name: String,
+    pub range: Option<(u128, u128)>,
+    pub function: RateFunction,
+    pub interval: Option<u128>,
+    pub fill: FillPolicy,
+}
+
+// Fetch every series satisfying all the label matchers, optionally restricted to the [lo, hi]
+// time range. The name of a series can be matched through the __name__ label.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct TsSelect {
+    pub matchers: Vec<Matcher>,
+    pub range: Option<(u128, u128)>,
+}
+
+// Aggregate every series satisfying all the label matchers over buckets of the given interval,
+// combining the series sharing the same values of the group_by labels into a single output series
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct TsAggregate {
+    pub matchers: Vec<Matcher>,
+    pub range: Option<(u128, u128)>,
+    pub aggregation: Aggregator,
+    pub interval: u128,
+    pub group_by: Vec<String>,
+    pub fill: FillPolicy,
 }
 
+// Metadata and statistics of a series, cheap as they're never computed on the points
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
-struct TsDelete {
-    name: String,
+pub struct TsInfo {
+    pub name: String,
+}
+
+// Admin command to save a snapshot of the whole keyspace, it has no arguments
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct TsSnapshot;
+
+// Replies sent back by the server, a plain acknowledgement carrying a status code for every
+// command but the query, which also carries the records of the requested series. Failures come
+// with a human-readable description of what went wrong.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct TsAck {
+    pub status: Status,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct TsQueryReply {
+    pub status: Status,
+    pub error: Option<String>,
+    pub records: Vec<Record>,
+    pub values: Vec<f64>,
+    pub buckets: Vec<(u128, Option<f64>)>,
+    pub cursor: Option<Vec<u8>>,
+}
+
+// A series selected by label matchers, with its records
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct TsSeries {
+    pub name: String,
+    pub labels: Labels,
+    pub records: Vec<Record>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct TsSelectReply {
+    pub status: Status,
+    pub error: Option<String>,
+    pub series: Vec<TsSeries>,
+}
+
+// An output series of an aggregation, the values of the group_by labels shared by the series it
+// combines and the start of each bucket paired with its aggregated value, if any
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct TsGroup {
+    pub labels: Labels,
+    pub points: Vec<(u128, Option<f64>)>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct TsAggregateReply {
+    pub status: Status,
+    pub error: Option<String>,
+    pub groups: Vec<TsGroup>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct TsInfoReply {
+    pub status: Status,
+    pub error: Option<String>,
+    pub labels: Labels,
+    pub retention: Option<Retention>,
+    pub duplicate_policy: Option<DuplicatePolicy>,
+    pub ctime: u128,
+    pub stats: Stats,
+}
+
+impl TsAck {
+    pub fn ok() -> TsAck {
+        TsAck {
+            status: Status::TsOk,
+            error: None,
+        }
+    }
+
+    pub fn error(status: Status, message: String) -> TsAck {
+        TsAck {
+            status,
+            error: Some(message),
+        }
+    }
+}
+
+impl TsQueryReply {
+    // A page of records, with the cursor to the next one unless it's the last
+    pub fn page(records: Vec<Record>, cursor: Option<Vec<u8>>) -> TsQueryReply {
+        TsQueryReply {
+            status: Status::TsOk,
+            error: None,
+            records,
+            values: Vec::new(),
+            buckets: Vec::new(),
+            cursor,
+        }
+    }
+
+    pub fn end_of_stream() -> TsQueryReply {
+        TsQueryReply {
+            status: Status::TsEndOfStream,
+            error: None,
+            records: Vec::new(),
+            values: Vec::new(),
+            buckets: Vec::new(),
+            cursor: None,
+        }
+    }
+
+    pub fn values(values: Vec<f64>) -> TsQueryReply {
+        TsQueryReply {
+            status: Status::TsOk,
+            error: None,
+            records: Vec::new(),
+            values,
+            buckets: Vec::new(),
+            cursor: None,
+        }
+    }
+
+    pub fn buckets(buckets: Vec<(u128, Option<f64>)>) -> TsQueryReply {
+        TsQueryReply {
+            status: Status::TsOk,
+            error: None,
+            records: Vec::new(),
+            values: Vec::new(),
+            buckets,
+            cursor: None,
+        }
+    }
+
+    pub fn error(status: Status, message: String) -> TsQueryReply {
+        TsQueryReply {
+            status,
+            error: Some(message),
+            records: Vec::new(),
+            values: Vec::new(),
+            buckets: Vec::new(),
+            cursor: None,
+        }
+    }
+}
+
+impl TsSelectReply {
+    pub fn series(series: Vec<TsSeries>) -> TsSelectReply {
+        TsSelectReply {
+            status: Status::TsOk,
+            error: None,
+            series,
+        }
+    }
+
+    pub fn error(status: Status, message: String) -> TsSelectReply {
+        TsSelectReply {
+            status,
+            error: Some(message),
+            series: Vec::new(),
+        }
+    }
+}
+
+impl TsAggregateReply {
+    pub fn groups(groups: Vec<TsGroup>) -> TsAggregateReply {
+        TsAggregateReply {
+            status: Status::TsOk,
+            error: None,
+            groups,
+        }
+    }
+
+    pub fn error(status: Status, message: String) -> TsAggregateReply {
+        TsAggregateReply {
+            status,
+            error: Some(message),
+            groups: Vec::new(),
+        }
+    }
+}
+
+impl TsInfoReply {
+    pub fn info(ts: &TimeSeries) -> TsInfoReply {
+        TsInfoReply {
+            status: Status::TsOk,
+            error: None,
+            labels: ts.labels().clone(),
+            retention: ts.retention(),
+            duplicate_policy: ts.duplicate_policy(),
+            ctime: ts.ctime(),
+            stats: ts.stats().clone(),
+        }
+    }
+
+    pub fn error(status: Status, message: String) -> TsInfoReply {
+        TsInfoReply {
+            status,
+            error: Some(message),
+            labels: Labels::new(),
+            retention: None,
+            duplicate_policy: None,
+            ctime: 0,
+            stats: Stats::default(),
+        }
+    }
 }
 
 impl<'a, T> TsPacket<'a, T>
@@ -99,36 +447,57 @@ where
     T: Serialize,
     T: Deserialize<'a>,
 {
-    pub fn from_binary(b: &'a Vec<u8>) -> Result<TsPacket<'a, T>, Box<bincode::ErrorKind>> {
-        if b.len() < 9 {
+    pub fn new(opcode: OpCode, packet: T) -> TsPacket<'a, T> {
+        let size = bincode::serialized_size(&packet).unwrap_or(0) as usize;
+        TsPacket {
+            header: TsHeader::new(opcode, size),
+            packet,
+            phantom: PhantomData,
+        }
+    }
+
+    // Build a response to a request, replies carry the same header byte of the request they
+    // answer to
+    pub fn reply(request: &TsHeader, packet: T) -> TsPacket<'a, T> {
+        let size = bincode::serialized_size(&packet).unwrap_or(0) as usize;
+        TsPacket {
+            header: TsHeader {
+                byte: request.byte,
+                size,
+            },
+            packet,
+            phantom: PhantomData,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn packet(&self) -> &T {
+        return &self.packet;
+    }
+
+    pub fn into_packet(self) -> T {
+        return self.packet;
+    }
+
+    pub fn from_binary(b: &'a [u8]) -> Result<TsPacket<'a, T>, Box<bincode::ErrorKind>> {
+        let header = TsHeader::from_binary(b)?;
+        // The size in the header delimits the packet, trailing bytes belong to the next frame
+        if b.len() - HEADER_SIZE < header.size {
             return Err(Box::new(bincode::ErrorKind::Custom(
                 "Not enough bytes".to_string(),
             )));
         }
-        let header: TsHeader = match bincode::deserialize(&b[..9]) {
-            Ok(h) => h,
-            Err(e) => return Err(e),
-        };
-        let packet = match bincode::deserialize(&b[9..]) {
-            Ok(p) => p,
-            Err(e) => return Err(e),
-        };
+        let packet = bincode::deserialize(&b[HEADER_SIZE..HEADER_SIZE + header.size])?;
         return Ok(TsPacket {
-            header: header,
-            packet: packet,
+            header,
+            packet,
             phantom: PhantomData,
         });
     }
 
     pub fn to_binary(&self) -> Result<Vec<u8>, Box<bincode::ErrorKind>> {
-        let mut h = match bincode::serialize(&self.header) {
-            Ok(h) => h,
-            Err(e) => return Err(e),
-        };
-        let mut p = match bincode::serialize(&self.packet) {
-            Ok(p) => p,
-            Err(e) => return Err(e),
-        };
+        let mut h = bincode::serialize(&self.header)?;
+        let mut p = bincode::serialize(&self.packet)?;
         h.append(&mut p);
         return Ok(h);
     }
@@ -148,16 +517,27 @@ mod tests {
         assert_eq!(h.opcode(), Some(OpCode::OpTsCreate));
     }
 
+    #[test]
+    fn test_header_new() {
+        let h = TsHeader::new(OpCode::OpTsQuery, 12);
+        assert_eq!(h.opcode(), Some(OpCode::OpTsQuery));
+        assert_eq!(h.size, 12);
+    }
+
     #[test]
     fn test_ts_packet_to_binary() {
         let tsp = TsPacket {
             header: TsHeader {
                 byte: OpCode::OpTsCreate as u8,
-                size: 10,
+                size: 66,
             },
             packet: TsCreate {
                 name: "ts-test".to_string(),
-                retention: 3000,
+                labels: vec![("host".to_string(), "web-1".to_string())]
+                    .into_iter()
+                    .collect(),
+                retention: Some(Retention::Count(3000)),
+                duplicate_policy: Some(DuplicatePolicy::Last),
             },
             phantom: PhantomData,
         };
@@ -170,4 +550,69 @@ mod tests {
     fn test_ts_packet_from_binary() {
         test_ts_packet_to_binary();
     }
+
+    #[test]
+    fn test_ts_packet_new() {
+        let tsp = TsPacket::new(
+            OpCode::OpTsAddPoint,
+            TsAddPoint {
+                name: "ts-test".to_string(),
+                timestamp: Some(1000),
+                value: 12.98,
+            },
+        );
+        let binary = tsp.to_binary().unwrap();
+        assert_eq!(binary.len(), HEADER_SIZE + tsp.header.size);
+        let decoded: TsPacket<TsAddPoint> = TsPacket::from_binary(&binary).unwrap();
+        assert_eq!(decoded.packet().timestamp, Some(1000));
+        assert_eq!(decoded.packet().value, 12.98);
+    }
+
+    #[test]
+    fn test_frame_len() {
+        let tsp = TsPacket::new(
+            OpCode::OpTsDelete,
+            TsDelete {
+                name: "ts-test".to_string(),
+            },
+        );
+        let mut binary = tsp.to_binary().unwrap();
+        let len = binary.len();
+        assert_eq!(frame_len(&binary[..HEADER_SIZE - 1]), None);
+        assert_eq!(frame_len(&binary[..len - 1]), None);
+        assert_eq!(frame_len(&binary), Some(len));
+        // Back-to-back frames, only the first one is delimited
+        binary.extend(tsp.to_binary().unwrap());
+        assert_eq!(frame_len(&binary), Some(len));
+        assert_eq!(frame_len(&binary[len..]), Some(len));
+    }
+
+    #[test]
+    fn test_ts_packet_from_binary_trailing_bytes() {
+        let tsp = TsPacket::new(
+            OpCode::OpTsDelete,
+            TsDelete {
+                name: "ts-test".to_string(),
+            },
+        );
+        let mut binary = tsp.to_binary().unwrap();
+        binary.extend(tsp.to_binary().unwrap());
+        let decoded: TsPacket<TsDelete> = TsPacket::from_binary(&binary).unwrap();
+        assert_eq!(tsp, decoded);
+        assert!(TsPacket::<TsDelete>::from_binary(&binary[..HEADER_SIZE + 4]).is_err());
+    }
+
+    #[test]
+    fn test_ts_packet_reply() {
+        let request = TsHeader::new(OpCode::OpTsDelete, 0);
+        let tsp = TsPacket::reply(
+            &request,
+            TsAck::error(Status::TsNotFount, "series not found".to_string()),
+        );
+        let binary = tsp.to_binary().unwrap();
+        let decoded: TsPacket<TsAck> = TsPacket::from_binary(&binary).unwrap();
+        assert_eq!(decoded.header.opcode(), Some(OpCode::OpTsDelete));
+        assert_eq!(decoded.packet().status, Status::TsNotFount);
+        assert_eq!(decoded.packet().error, Some("series not found".to_string()));
+    }
 }
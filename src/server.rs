@@ -24,144 +24,830 @@
 // OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+use crate::config::Config;
+use crate::dispatcher;
+use crate::keyspace::Keyspace;
+use crate::protocol::{self, Status, TsHeader};
+use crate::shard::{Response, Shards};
+use log::{debug, info, warn};
 use mio::net::{TcpListener, TcpStream};
-use mio::{Events, Interest, Poll, Token};
-use std::collections::HashMap;
+use mio::{Events, Interest, Poll, Token, Waker};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+use signal_hook_mio::v0_7::Signals;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::io::{Error, ErrorKind};
 use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-const BUFSIZE: usize = 4096;
-const MAXEVENTS: usize = 1024;
+// How long pending responses are given to be sent to the clients on shutdown
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+// Signals asking the server to shut down and the workers telling responses are ready, as the
+// listeners take the first tokens the last ones are reserved to them
+const SHUTDOWN_SIGNALS: [i32; 2] = [SIGINT, SIGTERM];
+const SIGNALS: Token = Token(usize::MAX);
+const WAKER: Token = Token(usize::MAX - 1);
+// Requests of a client handed to the workers at once, the following ones wait in its buffer
+const MAX_IN_FLIGHT: u64 = 64;
+
+// Bounds on the memory a single client can make the server hold, exceeding any of them gets it
+// disconnected
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub max_frame_size: usize,
+    pub max_input: usize,
+    pub max_output: usize,
+}
 
 // Simple client abstraction, composed by a TcpStream (basically a socket connection) and a
-// dedicated dynamic buffer, a vector of u8 type
+// dedicated dynamic buffer, a vector of u8 type, plus the queue of encoded responses waiting to be
+// sent back and the streamed query being replied to, if any. Requests are numbered as they're
+// handed to the workers, their responses are queued in the same order.
 pub struct Client {
     stream: TcpStream,
     buffer: Vec<u8>,
+    outbound: VecDeque<Vec<u8>>,
+    // Bytes of the response at the front of the queue already written to the socket
+    written: usize,
+    // Bytes of the queued responses not yet written
+    queued: usize,
+    interest: Interest,
+    // Sequence number of the next request and of the next response to queue, responses ready
+    // before the ones of the requests preceding them wait to be queued
+    seq: u64,
+    replied: u64,
+    completed: BTreeMap<u64, Vec<u8>>,
+    // The stream is held by a worker while producing its next frame
+    query: Option<dispatcher::Stream>,
+    streaming: bool,
+    limits: Limits,
 }
 
 impl Client {
-    pub fn new(socket: TcpStream) -> Client {
+    pub fn new(socket: TcpStream, limits: Limits) -> Client {
         Client {
             stream: socket,
             buffer: Vec::new(),
+            outbound: VecDeque::new(),
+            written: 0,
+            queued: 0,
+            interest: Interest::READABLE,
+            seq: 0,
+            replied: 0,
+            completed: BTreeMap::new(),
+            query: None,
+            streaming: false,
+            limits,
+        }
+    }
+
+    pub fn dump_buffer(&mut self, buffer: &[u8]) {
+        self.buffer.extend_from_slice(buffer);
+    }
+
+    // Drain the kernel queue into the client buffer till we're signaled with an EAGAIN/EWOULDBLOCK
+    // error, return false on a 0 read, which implies the client closed the connection. Reading
+    // stops early once the client exceeds the limits, it's going to be disconnected anyway.
+    pub fn receive(&mut self, buffer: &mut [u8]) -> Result<bool, Error> {
+        loop {
+            match self.stream.read(buffer) {
+                Ok(0) => return Ok(false),
+                Ok(n) => {
+                    self.dump_buffer(&buffer[..n]);
+                    if self.oversized(0).is_some() || self.buffer.len() > self.limits.max_input {
+                        return Ok(true);
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Size of the frame starting at offset of the buffer if it's over the limit, as soon as its
+    // header has been received
+    fn oversized(&self, offset: usize) -> Option<usize> {
+        match TsHeader::from_binary(&self.buffer[offset..]) {
+            Ok(header) if header.size() > self.limits.max_frame_size => return Some(header.size()),
+            _ => return None,
         }
     }
 
-    pub fn dump_buffer(&mut self, buffer: &[u8; BUFSIZE], n: usize) {
-        for b in &buffer[0..n] {
-            self.buffer.push(*b);
+    // Why the client is to be disconnected, if it exceeds any of the limits
+    fn exceeded(&self) -> Option<String> {
+        if let Some(size) = self.oversized(0) {
+            return Some(format!(
+                "Frame of {} bytes exceeds the limit of {}",
+                size, self.limits.max_frame_size
+            ));
         }
+        if self.buffer.len() > self.limits.max_input {
+            return Some(format!(
+                "More than {} bytes of requests waiting to be run",
+                self.limits.max_input
+            ));
+        }
+        if self.queued > self.limits.max_output {
+            return Some(format!(
+                "More than {} bytes of responses waiting to be read",
+                self.limits.max_output
+            ));
+        }
+        return None;
+    }
+
+    // Make an attempt at telling the client why it's being disconnected, without waiting for the
+    // socket to be writable. Responses not yet started are dropped, a partially written one has to
+    // be completed for the error frame to be readable.
+    fn reject(&mut self, reason: String) -> Result<(), Error> {
+        self.outbound.truncate(if self.written > 0 { 1 } else { 0 });
+        self.queued = self.outbound.front().map_or(0, |r| r.len() - self.written);
+        self.queue(dispatcher::error_frame(Status::TsLimitExceeded, reason));
+        return self.send();
     }
 
-    pub fn clear_buffer(&mut self) {
-        self.buffer.clear();
+    fn queue(&mut self, response: Vec<u8>) {
+        self.queued += response.len();
+        self.outbound.push_back(response);
     }
 
+    // Take the response to a request, queueing it once the responses to every request before it
+    // have been queued
+    pub fn complete(&mut self, seq: u64, response: Response) {
+        let frame = match response {
+            Response::Frame(frame) => frame,
+            Response::Stream(frame, rest) => {
+                self.streaming = rest.is_some();
+                self.query = rest.map(|stream| *stream);
+                frame
+            }
+        };
+        self.completed.insert(seq, frame);
+        while let Some(frame) = self.completed.remove(&self.replied) {
+            self.replied += 1;
+            // Requests without a valid header get no response
+            if !frame.is_empty() {
+                self.queue(frame);
+            }
+        }
+    }
+
+    fn in_flight(&self) -> u64 {
+        return self.seq - self.replied;
+    }
+
+    // Hand every complete frame received so far to the workers, in order, up to the maximum in
+    // flight. A trailing partial frame is kept in the buffer waiting for the next read.
+    //
+    // A streamed query has a single frame at a time produced, once the responses to the requests
+    // before it and its previous frame have been sent, and the requests following it wait in the
+    // buffer until the stream ends.
+    pub fn process_frames(&mut self, shards: &mut Shards, token: Token) {
+        let mut offset = 0;
+        loop {
+            if self.streaming {
+                if self.in_flight() == 0 && self.outbound.is_empty() {
+                    if let Some(query) = self.query.take() {
+                        shards.resume(token, self.seq, query);
+                        self.seq += 1;
+                    }
+                }
+                break;
+            }
+            // Requests over the limits are left in the buffer, the client is disconnected. Past the
+            // maximum in flight they wait for the responses to the previous ones instead.
+            if self.in_flight() >= MAX_IN_FLIGHT
+                || self.queued > self.limits.max_output
+                || self.oversized(offset).is_some()
+            {
+                break;
+            }
+            let len = match protocol::frame_len(&self.buffer[offset..]) {
+                Some(len) => len,
+                None => break,
+            };
+            let frame = &self.buffer[offset..offset + len];
+            match dispatcher::stream(frame) {
+                Some(query) => {
+                    self.query = Some(query);
+                    self.streaming = true;
+                }
+                None => {
+                    shards.submit(token, self.seq, frame);
+                    self.seq += 1;
+                }
+            }
+            offset += len;
+        }
+        self.buffer.drain(..offset);
+    }
+
+    // Write the queued responses till the socket would block, keeping track of how much of the
+    // first one has been written to resume from there once it's writable again
     pub fn send(&mut self) -> Result<(), Error> {
-        return self.stream.write_all(&self.buffer);
+        while let Some(response) = self.outbound.front() {
+            match self.stream.write(&response[self.written..]) {
+                Ok(0) => return Err(Error::from(ErrorKind::WriteZero)),
+                Ok(n) => {
+                    self.written += n;
+                    self.queued -= n;
+                    if self.written == response.len() {
+                        self.outbound.pop_front();
+                        self.written = 0;
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        return Ok(());
     }
 
-    pub fn register_read(&mut self, poll: &mut Poll, token: Token) {
-        poll.registry()
-            .register(&mut self.stream, token, Interest::READABLE)
-            .unwrap();
+    // Hand the requests received to the workers and send back as much of the responses ready as
+    // the socket takes, requesting the following frames of a stream as the previous ones are sent
+    pub fn flush(&mut self, shards: &mut Shards, token: Token) -> Result<(), Error> {
+        loop {
+            self.process_frames(shards, token);
+            if !self.has_pending() {
+                return Ok(());
+            }
+            self.send()?;
+            if self.has_pending() {
+                return Ok(());
+            }
+        }
     }
 
-    pub fn register_write(&mut self, poll: &mut Poll, token: Token) {
-        poll.registry()
-            .register(&mut self.stream, token, Interest::WRITABLE)
-            .unwrap();
+    pub fn has_pending(&self) -> bool {
+        return !self.outbound.is_empty();
+    }
+
+    // Whether every request received has been replied to and the responses sent
+    pub fn is_idle(&self) -> bool {
+        return !self.has_pending() && self.in_flight() == 0 && !self.streaming;
+    }
+
+    // Handle a readiness event of the socket or responses being ready, reading new requests unless
+    // draining on shutdown, then running them and sending back their responses. Return false once
+    // the connection is over, closed by the client or, when draining, with every response sent.
+    pub fn handle(
+        &mut self,
+        shards: &mut Shards,
+        poll: &mut Poll,
+        token: Token,
+        readable: bool,
+        draining: bool,
+        buffer: &mut [u8],
+    ) -> Result<bool, Error> {
+        let open = !readable || draining || self.receive(buffer)?;
+        // A client closing right after its last requests still gets what can be sent right away
+        self.flush(shards, token)?;
+        if let Some(reason) = self.exceeded() {
+            warn!("Disconnecting client {:?}: {}", token, reason);
+            self.reject(reason)?;
+            return Ok(false);
+        }
+        if !open || (draining && self.is_idle()) {
+            return Ok(false);
+        }
+        self.reregister(poll, token)?;
+        return Ok(true);
+    }
+
+    pub fn register(&mut self, poll: &mut Poll, token: Token) -> Result<(), Error> {
+        self.interest = Interest::READABLE;
+        return poll
+            .registry()
+            .register(&mut self.stream, token, self.interest);
+    }
+
+    // Wait for the socket to be writable as well only while there are responses left to send
+    pub fn reregister(&mut self, poll: &mut Poll, token: Token) -> Result<(), Error> {
+        let interest = if self.has_pending() {
+            Interest::READABLE | Interest::WRITABLE
+        } else {
+            Interest::READABLE
+        };
+        if interest != self.interest {
+            poll.registry()
+                .reregister(&mut self.stream, token, interest)?;
+            self.interest = interest;
+        }
+        return Ok(());
     }
 }
 
-// Utterly simple server object, just an IPv4 address and a port plus a mapping of the connected
-// clients
+// Utterly simple server object, just the addresses to listen on and the limits set by the
+// configuration plus a mapping of the connected clients and the shards of the keyspace holding all
+// the timeseries, each one handed to a worker thread once running
 pub struct Server {
-    addr: String,
-    port: i32,
+    addrs: Vec<SocketAddr>,
+    max_clients: usize,
+    buffer_size: usize,
+    max_events: usize,
+    limits: Limits,
     connections: HashMap<Token, Client>,
+    keyspaces: Vec<Keyspace>,
 }
 
 impl Server {
-    pub fn new(addr: String, port: i32) -> Server {
+    pub fn new(config: &Config, keyspaces: Vec<Keyspace>) -> Server {
         Server {
-            addr,
-            port,
+            addrs: config.bind.clone(),
+            max_clients: config.max_clients,
+            buffer_size: config.buffer_size,
+            max_events: config.max_events,
+            limits: Limits {
+                max_frame_size: config.max_frame_size,
+                max_input: config.max_input_buffer,
+                max_output: config.max_output_buffer,
+            },
             connections: HashMap::new(),
+            keyspaces,
         }
     }
 
-    fn to_addr(&self) -> std::net::SocketAddr {
-        return format!("{}:{}", self.addr, self.port).parse().unwrap();
-    }
-
     pub fn run(&mut self) -> Result<(), Error> {
-        let mut counter = 0;
-        let mut buffer = [0 as u8; BUFSIZE];
-        let mut listener = TcpListener::bind(self.to_addr()).unwrap();
+        let mut buffer = vec![0_u8; self.buffer_size];
+        let mut listeners = Vec::new();
+        for addr in &self.addrs {
+            let listener = TcpListener::bind(*addr)
+                .map_err(|e| Error::new(e.kind(), format!("cannot listen on {}: {}", addr, e)))?;
+            info!("Listening on {}", addr);
+            listeners.push(listener);
+        }
+        // Listeners take the first tokens, clients the following ones
+        let mut counter = listeners.len() - 1;
         // Poll interface will take care of choosing the right IO multiplexing implementation found
         // on the host
-        let mut poll = Poll::new().unwrap();
-        // Register the listener socket for read events
+        let mut poll = Poll::new()?;
+        // Register the listener sockets for read events
+        for (i, listener) in listeners.iter_mut().enumerate() {
+            poll.registry()
+                .register(listener, Token(i), Interest::READABLE)?;
+        }
+        // A second signal received while shutting down terminates the process right away
+        let terminating = Arc::new(AtomicBool::new(false));
+        for signal in &SHUTDOWN_SIGNALS {
+            flag::register_conditional_shutdown(*signal, 1, Arc::clone(&terminating))?;
+            flag::register(*signal, Arc::clone(&terminating))?;
+        }
+        let mut signals = Signals::new(SHUTDOWN_SIGNALS)?;
         poll.registry()
-            .register(&mut listener, Token(0), Interest::READABLE)
-            .unwrap();
-        let mut events = Events::with_capacity(MAXEVENTS);
+            .register(&mut signals, SIGNALS, Interest::READABLE)?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let keyspaces = std::mem::take(&mut self.keyspaces);
+        info!("Serving {} shards", keyspaces.len());
+        let mut shards = Shards::start(keyspaces, waker)?;
+        let mut events = Events::with_capacity(self.max_events);
+        // Set on shutdown, when the time to drain the pending responses runs out
+        let mut deadline: Option<Instant> = None;
         loop {
-            // Blocking call, wait for kernel to notify sockets to be ready for read/write
-            poll.poll(&mut events, None)?;
+            if let Some(deadline) = deadline {
+                if self.connections.is_empty() || Instant::now() >= deadline {
+                    break;
+                }
+            }
+            // Blocking call, wait for kernel to notify sockets to be ready for read/write or the
+            // workers to have responses ready, waking up anyway at the end of the shutdown
+            let timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            if let Err(e) = poll.poll(&mut events, timeout) {
+                // Signals interrupt the wait, they're handled as events on the next one
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
             for event in events.iter() {
                 match event.token() {
-                    Token(0) => loop {
+                    SIGNALS => {
+                        // Drain every pending signal, later ones are handled by the flag
+                        if signals.pending().count() == 0 || deadline.is_some() {
+                            continue;
+                        }
+                        info!("Shutting down, a second signal forces the exit");
+                        // Stop accepting new connections and new requests, just the responses to
+                        // the ones already received are still sent
+                        for listener in listeners.iter_mut() {
+                            poll.registry().deregister(listener)?;
+                        }
+                        listeners.clear();
+                        self.connections.retain(|token, client| {
+                            return client.flush(&mut shards, *token).is_ok()
+                                && !client.is_idle()
+                                && client.reregister(&mut poll, *token).is_ok();
+                        });
+                        deadline = Some(Instant::now() + DRAIN_TIMEOUT);
+                    }
+                    WAKER => {
+                        let mut ready = Vec::new();
+                        for (token, seq, response) in shards.completed() {
+                            // Responses to clients already gone are dropped
+                            if let Some(client) = self.connections.get_mut(&token) {
+                                client.complete(seq, response);
+                                ready.push(token);
+                            }
+                        }
+                        ready.sort();
+                        ready.dedup();
+                        for token in ready {
+                            let draining = deadline.is_some();
+                            self.serve(&mut shards, &mut poll, token, false, draining, &mut buffer);
+                        }
+                    }
+                    Token(i) if i < listeners.len() => loop {
                         // A new connection (possibly more than one) arrived, we accept it and
                         // track it inserting it into the server hashmap
-                        match listener.accept() {
-                            Ok((socket, _)) => {
+                        match listeners[i].accept() {
+                            // Refused connections are just dropped, closing them
+                            Ok((_, addr)) if self.connections.len() >= self.max_clients => {
+                                warn!("Refusing {}, too many clients connected", addr);
+                            }
+                            Ok((socket, addr)) => {
                                 counter += 1;
                                 let token = Token(counter);
-                                let mut client = Client::new(socket);
-                                client.register_read(&mut poll, token);
+                                let mut client = Client::new(socket, self.limits);
+                                if let Err(e) = client.register(&mut poll, token) {
+                                    warn!("Cannot register {}: {}", addr, e);
+                                    continue;
+                                }
                                 self.connections.insert(token, client);
+                                debug!("Client {} connected as {:?}", addr, token);
                             }
                             Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
                             Err(_) => break,
                         }
                     },
-                    token if event.is_readable() => loop {
-                        // Some data arrived to be read from the socket, we drain the kernel queue
-                        // into the buffer till we're signaled with an EAGAIN/EWOULDBLOCK error or
-                        // a 0 return (which imply client closed the connection)
-                        let read = self
-                            .connections
-                            .get_mut(&token)
-                            .unwrap()
-                            .stream
-                            .read(&mut buffer);
-                        match read {
-                            // Connection closed
-                            Ok(0) => {
-                                self.connections.remove(&token);
-                                break;
-                            }
-                            // We copy n read bytes into the client buffer
-                            Ok(n) => {
-                                let client = self.connections.get_mut(&token).unwrap();
-                                client.dump_buffer(&buffer, n);
-                            }
-                            Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
-                            Err(_) => break,
-                        }
-                    },
-                    token if event.is_writable() => {
-                        let client = self.connections.get_mut(&token).unwrap();
-                        client.send().unwrap();
-                        // Re-use existing connection, switch back to reading wait
-                        client.register_read(&mut poll, token);
+                    token => {
+                        let readable = event.is_readable();
+                        let draining = deadline.is_some();
+                        self.serve(
+                            &mut shards,
+                            &mut poll,
+                            token,
+                            readable,
+                            draining,
+                            &mut buffer,
+                        );
                     }
-                    _ => unreachable!(),
                 }
             }
         }
+        if !self.connections.is_empty() {
+            warn!(
+                "Closing {} clients with responses still pending",
+                self.connections.len()
+            );
+        }
+        self.connections.clear();
+        shards.stop()?;
+        info!("Shutdown complete");
+        return Ok(());
+    }
+
+    // Handle a client, disconnecting it once the connection is over. Failures only affect the
+    // client, which is disconnected as well.
+    fn serve(
+        &mut self,
+        shards: &mut Shards,
+        poll: &mut Poll,
+        token: Token,
+        readable: bool,
+        draining: bool,
+        buffer: &mut [u8],
+    ) {
+        // Clients already gone, closed handling a previous event of the same batch
+        let client = match self.connections.get_mut(&token) {
+            Some(client) => client,
+            None => return,
+        };
+        match client.handle(shards, poll, token, readable, draining, buffer) {
+            Ok(true) => (),
+            Ok(false) => {
+                self.connections.remove(&token);
+                debug!("Client {:?} disconnected", token);
+            }
+            Err(e) => {
+                self.connections.remove(&token);
+                debug!("Client {:?} disconnected: {}", token, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::index::Labels;
+    use crate::protocol::{OpCode, TsAck, TsCreate, TsDelete, TsPacket, HEADER_SIZE};
+    use std::net;
+    use std::thread;
+
+    const LIMITS: Limits = Limits {
+        max_frame_size: 1 << 20,
+        max_input: 1 << 20,
+        max_output: 1 << 20,
+    };
+    // Larger than the socket buffers of the loopback, for the peer to fill them not reading
+    const LARGE: usize = 16 << 20;
+
+    // A client over a loopback connection, along with the socket of its peer
+    fn connect(limits: Limits) -> (Client, net::TcpStream) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let (socket, _) = listener.accept().unwrap();
+        socket.set_nonblocking(true).unwrap();
+        return (Client::new(TcpStream::from_std(socket), limits), peer);
+    }
+
+    fn start() -> (Poll, Shards) {
+        let poll = Poll::new().unwrap();
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER).unwrap());
+        return (poll, Shards::start(vec![Keyspace::new()], waker).unwrap());
+    }
+
+    // Handle the client once what its peer sent has been received, return whether it's still
+    // connected
+    fn receive(client: &mut Client, poll: &mut Poll, shards: &mut Shards) -> bool {
+        let token = Token(1);
+        let mut events = Events::with_capacity(16);
+        while !events.iter().any(|e| e.token() == token && e.is_readable()) {
+            poll.poll(&mut events, Some(Duration::from_secs(10)))
+                .unwrap();
+        }
+        return client
+            .handle(shards, poll, token, true, false, &mut [0; 4096])
+            .unwrap();
+    }
+
+    // Queue the responses to the requests in flight once the shard has run them, and send them
+    fn respond(client: &mut Client, poll: &mut Poll, shards: &mut Shards) {
+        while client.in_flight() > 0 {
+            for (_, seq, response) in shards.completed() {
+                client.complete(seq, response);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        let handled = client.handle(shards, poll, Token(1), false, false, &mut [0; 16]);
+        assert!(handled.unwrap());
+    }
+
+    fn read_ack(peer: &mut net::TcpStream) -> TsAck {
+        let mut frame = vec![0; HEADER_SIZE];
+        peer.read_exact(&mut frame).unwrap();
+        let size = TsHeader::from_binary(&frame).unwrap().size();
+        frame.resize(HEADER_SIZE + size, 0);
+        peer.read_exact(&mut frame[HEADER_SIZE..]).unwrap();
+        return TsPacket::from_binary(&frame).unwrap().into_packet();
+    }
+
+    fn create(name: &str) -> Vec<u8> {
+        let create = TsCreate {
+            name: name.to_string(),
+            labels: Labels::new(),
+            retention: None,
+            duplicate_policy: None,
+        };
+        return TsPacket::new(OpCode::OpTsCreate, create)
+            .to_binary()
+            .unwrap();
+    }
+
+    // The error a client is told about last, right before being disconnected
+    fn rejection(frame: &[u8]) -> TsAck {
+        assert_eq!(protocol::frame_len(frame), Some(frame.len()));
+        let header = TsHeader::from_binary(frame).unwrap();
+        assert_eq!(header.opcode(), Some(OpCode::OpTsError));
+        let ack: TsAck = TsPacket::from_binary(frame).unwrap().into_packet();
+        assert_eq!(ack.status, Status::TsLimitExceeded);
+        return ack;
+    }
+
+    // Bytes of the queued responses not yet written
+    fn unsent(client: &Client) -> usize {
+        return client.outbound.iter().map(|r| r.len()).sum::<usize>() - client.written;
+    }
+
+    #[test]
+    fn test_client_send() {
+        let (mut client, mut peer) = connect(LIMITS);
+        let large: Vec<u8> = (0..LARGE).map(|i| (i % 251) as u8).collect();
+        client.queue(large.clone());
+        client.queue(b"small".to_vec());
+        assert_eq!(client.queued, LARGE + 5);
+        client.send().unwrap();
+        assert!(client.written > 0 && client.written < LARGE);
+        assert_eq!(client.queued, unsent(&client));
+        // Writing resumes from where it stopped as the peer reads
+        let mut received = Vec::new();
+        let mut buffer = vec![0; 1 << 16];
+        while client.has_pending() {
+            let n = peer.read(&mut buffer).unwrap();
+            received.extend_from_slice(&buffer[..n]);
+            client.send().unwrap();
+            assert_eq!(client.queued, unsent(&client));
+        }
+        assert_eq!((client.written, client.queued), (0, 0));
+        drop(client);
+        peer.read_to_end(&mut received).unwrap();
+        assert_eq!(received.len(), LARGE + 5);
+        assert!(received[..LARGE] == large[..]);
+        assert_eq!(&received[LARGE..], b"small");
+    }
+
+    #[test]
+    fn test_client_reregister() {
+        let (mut client, mut peer) = connect(LIMITS);
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(16);
+        let token = Token(1);
+        client.register(&mut poll, token).unwrap();
+        peer.write_all(b"x").unwrap();
+        poll.poll(&mut events, Some(Duration::from_secs(10)))
+            .unwrap();
+        assert!(events.iter().any(|e| e.token() == token && e.is_readable()));
+        assert!(client.receive(&mut [0; 16]).unwrap());
+        assert_eq!(client.buffer, b"x");
+        client.reregister(&mut poll, token).unwrap();
+        assert_eq!(client.interest, Interest::READABLE);
+        // Writability is waited for only while some responses couldn't be sent right away
+        client.queue(vec![0; LARGE]);
+        client.send().unwrap();
+        client.reregister(&mut poll, token).unwrap();
+        assert_eq!(client.interest, Interest::READABLE | Interest::WRITABLE);
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            peer.read_to_end(&mut received).unwrap();
+            return received.len();
+        });
+        while client.has_pending() {
+            poll.poll(&mut events, Some(Duration::from_secs(10)))
+                .unwrap();
+            assert!(events.iter().any(|e| e.token() == token && e.is_writable()));
+            client.send().unwrap();
+            client.reregister(&mut poll, token).unwrap();
+        }
+        assert_eq!(client.interest, Interest::READABLE);
+        drop(client);
+        assert_eq!(reader.join().unwrap(), LARGE);
+    }
+
+    #[test]
+    fn test_client_complete() {
+        let (mut client, mut peer) = connect(LIMITS);
+        client.seq = 4;
+        // Responses wait for the ones of the requests before them
+        client.complete(2, Response::Frame(b"third".to_vec()));
+        client.complete(1, Response::Frame(b"second".to_vec()));
+        assert!(!client.has_pending());
+        assert_eq!(client.in_flight(), 4);
+        client.complete(0, Response::Frame(b"first".to_vec()));
+        assert_eq!(client.in_flight(), 1);
+        assert_eq!(client.queued, 16);
+        // Requests without a valid header get no response
+        client.complete(3, Response::Frame(Vec::new()));
+        assert_eq!(client.in_flight(), 0);
+        assert_eq!(client.outbound.len(), 3);
+        client.send().unwrap();
+        assert!(client.is_idle());
+        drop(client);
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"firstsecondthird");
+    }
+
+    #[test]
+    fn test_client_oversized() {
+        let (mut poll, mut shards) = start();
+        let (mut client, mut peer) = connect(LIMITS);
+        client.register(&mut poll, Token(1)).unwrap();
+        // The frame is refused as soon as its header is received
+        let header = TsHeader::new(OpCode::OpTsDelete, LIMITS.max_frame_size + 1);
+        peer.write_all(&bincode::serialize(&header).unwrap())
+            .unwrap();
+        assert!(!receive(&mut client, &mut poll, &mut shards));
+        drop(client);
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).unwrap();
+        let expected = format!(
+            "Frame of {} bytes exceeds the limit of {}",
+            (1 << 20) + 1,
+            1 << 20
+        );
+        assert_eq!(rejection(&received).error, Some(expected));
+        shards.stop().unwrap();
+    }
+
+    #[test]
+    fn test_client_input_exceeded() {
+        let (mut poll, mut shards) = start();
+        let limits = Limits {
+            max_input: 64,
+            ..LIMITS
+        };
+        let (mut client, mut peer) = connect(limits);
+        client.register(&mut poll, Token(1)).unwrap();
+        // A partial frame within the size limit, but larger than the buffer can hold
+        let header = TsHeader::new(OpCode::OpTsDelete, 500);
+        peer.write_all(&bincode::serialize(&header).unwrap())
+            .unwrap();
+        peer.write_all(&[0; 100]).unwrap();
+        assert!(!receive(&mut client, &mut poll, &mut shards));
+        drop(client);
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).unwrap();
+        let expected = "More than 64 bytes of requests waiting to be run".to_string();
+        assert_eq!(rejection(&received).error, Some(expected));
+        shards.stop().unwrap();
+    }
+
+    #[test]
+    fn test_client_output_exceeded() {
+        let (mut poll, mut shards) = start();
+        let (mut client, mut peer) = connect(LIMITS);
+        client.register(&mut poll, Token(1)).unwrap();
+        let large: Vec<u8> = (0..LARGE).map(|i| (i % 251) as u8).collect();
+        client.queue(large.clone());
+        client.queue(b"small".to_vec());
+        client.send().unwrap();
+        let handled = client.handle(&mut shards, &mut poll, Token(1), false, false, &mut [0; 16]);
+        assert!(!handled.unwrap());
+        // The response partially written is completed for the error frame to follow it, the
+        // others are dropped
+        assert_eq!(client.outbound.len(), 2);
+        assert_eq!(client.outbound[0], large);
+        assert_eq!(client.queued, unsent(&client));
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            peer.read_to_end(&mut received).unwrap();
+            return received;
+        });
+        while client.has_pending() {
+            client.send().unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(client);
+        let received = reader.join().unwrap();
+        assert!(received[..LARGE] == large[..]);
+        let expected = "More than 1048576 bytes of responses waiting to be read".to_string();
+        assert_eq!(rejection(&received[LARGE..]).error, Some(expected));
+        shards.stop().unwrap();
+    }
+
+    #[test]
+    fn test_client_split_frame() {
+        let (mut poll, mut shards) = start();
+        let (mut client, mut peer) = connect(LIMITS);
+        client.register(&mut poll, Token(1)).unwrap();
+        // The first part of a frame waits in the buffer for the rest of it
+        let frame = create("cpu");
+        let half = frame.len() / 2;
+        peer.write_all(&frame[..half]).unwrap();
+        assert!(receive(&mut client, &mut poll, &mut shards));
+        assert_eq!((client.seq, &client.buffer[..]), (0, &frame[..half]));
+        peer.write_all(&frame[half..]).unwrap();
+        assert!(receive(&mut client, &mut poll, &mut shards));
+        assert_eq!(client.seq, 1);
+        assert!(client.buffer.is_empty());
+        respond(&mut client, &mut poll, &mut shards);
+        assert_eq!(read_ack(&mut peer), TsAck::ok());
+        shards.stop().unwrap();
+    }
+
+    #[test]
+    fn test_client_many_frames() {
+        let (mut poll, mut shards) = start();
+        let (mut client, mut peer) = connect(LIMITS);
+        client.register(&mut poll, Token(1)).unwrap();
+        // Every frame of a single read is run, in order
+        let delete = TsDelete {
+            name: "mem".to_string(),
+        };
+        let mut frames = create("cpu");
+        frames.extend(create("cpu"));
+        frames.extend(
+            TsPacket::new(OpCode::OpTsDelete, delete)
+                .to_binary()
+                .unwrap(),
+        );
+        peer.write_all(&frames).unwrap();
+        assert!(receive(&mut client, &mut poll, &mut shards));
+        assert_eq!(client.seq, 3);
+        assert!(client.buffer.is_empty());
+        respond(&mut client, &mut poll, &mut shards);
+        let statuses: Vec<Status> = (0..3).map(|_| read_ack(&mut peer).status).collect();
+        assert_eq!(
+            statuses,
+            vec![Status::TsOk, Status::TsExists, Status::TsNotFount]
+        );
+        shards.stop().unwrap();
     }
 }
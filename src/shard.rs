@@ -0,0 +1,1057 @@
+// BSD 2-Clause License
+//
+// Copyright (c) 2020, Andrea Giacomo Baldan
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+//   list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+//   this list of conditions and the following disclaimer in the documentation
+//   and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::dispatcher::{self, Groups, Stream};
+use crate::keyspace::Keyspace;
+use crate::protocol::{
+    OpCode, Status, TsAck, TsAddPoint, TsAggregate, TsAggregateReply, TsCreate, TsDelete, TsHeader,
+    TsInfo, TsMaddPoint, TsPacket, TsQuery, TsRate, TsSelect, TsSelectReply, TsSeries, TsSnapshot,
+};
+use crate::timeseries::{self, Record};
+use crate::wal::FsyncPolicy;
+use log::{error, warn};
+use mio::{Token, Waker};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// How often expired points are swept away from every timeseries and the write-ahead log synced to
+// disk
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// How often a snapshot of the whole keyspace is saved
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(300);
+// File of a sharded data directory recording the number of shards
+const SHARDS_FILE: &str = "shards";
+
+// The shard owning a series, the hash of the name must not change across releases or the series
+// persisted wouldn't be found in their shard anymore, hence FNV-1a rather than the std hasher
+pub fn shard_of(name: &str, shards: usize) -> usize {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in name.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    return (hash % shards as u64) as usize;
+}
+
+// Open the keyspace of every shard. A single one lives right in the data directory, otherwise each
+// one has a directory of its own in it and the number of shards is recorded, as series would be
+// looked for in the wrong shard after changing it.
+pub fn open(dir: &Path, shards: usize, fsync: FsyncPolicy) -> Result<Vec<Keyspace>, Error> {
+    let marker = dir.join(SHARDS_FILE);
+    let sharded = match fs::read_to_string(&marker) {
+        Ok(content) => Some(content.trim().parse::<usize>().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid number of shards in {}", marker.display()),
+            )
+        })?),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    match sharded {
+        Some(n) if n != shards => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("data is sharded across {} workers, not {}", n, shards),
+            ))
+        }
+        None if shards > 1 && Keyspace::exists(dir) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "data isn't sharded, it can only be served by a single worker".to_string(),
+            ))
+        }
+        None if shards == 1 => return Ok(vec![Keyspace::open(dir, fsync)?]),
+        _ => (),
+    }
+    if sharded.is_none() {
+        fs::create_dir_all(dir)?;
+        fs::write(&marker, format!("{}\n", shards))?;
+    }
+    return (0..shards)
+        .map(|i| {
+            let shard_dir = dir.join(format!("shard-{}", i));
+            fs::create_dir_all(&shard_dir)?;
+            return Keyspace::open(&shard_dir, fsync);
+        })
+        .collect();
+}
+
+// Work handed to the owner of a shard. Requests spanning multiple shards are split in parts, each
+// one run by a shard, their outcomes merged back into a single response.
+enum Job {
+    Request(Vec<u8>),
+    Stream(Stream),
+    // Part of a batch of points to validate, its points are then stored by a commit only once
+    // every other part has been validated as well
+    Prepare(TsMaddPoint),
+    Commit(Vec<(String, Record)>),
+    Snapshot,
+    Select(TsSelect),
+    Aggregate(TsAggregate),
+    // Makes the worker panic
+    #[cfg(test)]
+    Panic,
+}
+
+impl Job {
+    // The outcome of the job when its shard isn't running anymore, so that the request it comes
+    // from still gets a response
+    fn failure(&self) -> fn() -> Outcome {
+        return match self {
+            Job::Request(_) => || {
+                let frame = dispatcher::error_frame(Status::TsServerError, unavailable());
+                return Outcome::Response(Response::Frame(frame));
+            },
+            Job::Stream(_) => || {
+                let frame = dispatcher::error_frame(Status::TsServerError, unavailable());
+                return Outcome::Response(Response::Stream(frame, None));
+            },
+            Job::Prepare(_) => {
+                || Outcome::Prepared(Err(TsAck::error(Status::TsServerError, unavailable())))
+            }
+            Job::Commit(_) | Job::Snapshot => {
+                || Outcome::Ack(TsAck::error(Status::TsServerError, unavailable()))
+            }
+            Job::Select(_) => {
+                || Outcome::Series(TsSelectReply::error(Status::TsServerError, unavailable()))
+            }
+            Job::Aggregate(_) => || {
+                let reply = TsAggregateReply::error(Status::TsServerError, unavailable());
+                return Outcome::Groups(Err(reply));
+            },
+            #[cfg(test)]
+            Job::Panic => || {
+                let frame = dispatcher::error_frame(Status::TsServerError, unavailable());
+                return Outcome::Response(Response::Frame(frame));
+            },
+        };
+    }
+}
+
+fn unavailable() -> String {
+    return "Shard unavailable, its worker stopped".to_string();
+}
+
+// A job tagged with the client and the sequence number of the request it comes from
+struct Task {
+    token: Token,
+    seq: u64,
+    part: usize,
+    job: Job,
+}
+
+// Response to a request, along with the stream to resume for its next frame if it's not over
+pub enum Response {
+    Frame(Vec<u8>),
+    Stream(Vec<u8>, Option<Box<Stream>>),
+}
+
+enum Outcome {
+    Response(Response),
+    Ack(TsAck),
+    Prepared(Result<Vec<(String, Record)>, TsAck>),
+    Series(TsSelectReply),
+    Groups(Result<Groups, TsAggregateReply>),
+}
+
+struct Done {
+    token: Token,
+    seq: u64,
+    part: usize,
+    outcome: Outcome,
+}
+
+// What the parts of a request add up to
+enum Partial {
+    Acks,
+    // The parts of a batch are done twice, once validated and once stored, their points are
+    // committed only if all of them are valid. Workers never wait for the others meanwhile, so
+    // a part is validated again when committed, failing on its own if another client wrote to
+    // its series in between, as it does if its write-ahead log can't be written. The other parts
+    // are stored nonetheless and the error is replied, such batches aren't atomic.
+    Batch {
+        shards: Vec<usize>,
+        prepared: Vec<Vec<(String, Record)>>,
+        committing: bool,
+    },
+    Series(Vec<TsSeries>),
+    // Groups of every shard by shard index, merged in that order whatever the order they came in
+    Groups(TsAggregate, BTreeMap<usize, Groups>),
+}
+
+// A request split across shards, waiting for the outcomes of its parts
+struct Merge {
+    header: TsHeader,
+    pending: usize,
+    // The failure of the earliest part, replied in place of the merged result
+    failure: Option<(usize, Status, String)>,
+    partial: Partial,
+}
+
+impl Merge {
+    fn new(header: TsHeader, parts: usize, partial: Partial) -> Merge {
+        Merge {
+            header,
+            pending: parts,
+            failure: None,
+            partial,
+        }
+    }
+
+    fn add(&mut self, part: usize, outcome: Outcome) {
+        self.pending -= 1;
+        let (status, error) = match (outcome, &mut self.partial) {
+            (Outcome::Ack(ack), _) => (ack.status, ack.error),
+            (Outcome::Prepared(Ok(points)), Partial::Batch { prepared, .. }) => {
+                prepared[part] = points;
+                (Status::TsOk, None)
+            }
+            (Outcome::Prepared(Err(ack)), _) => (ack.status, ack.error),
+            (Outcome::Series(reply), Partial::Series(series)) => {
+                series.extend(reply.series);
+                (reply.status, reply.error)
+            }
+            (Outcome::Groups(Ok(groups)), Partial::Groups(_, parts)) => {
+                parts.insert(part, groups);
+                (Status::TsOk, None)
+            }
+            (Outcome::Groups(Err(reply)), _) => (reply.status, reply.error),
+            _ => return,
+        };
+        let earliest = self.failure.as_ref().is_none_or(|(p, _, _)| part < *p);
+        if status != Status::TsOk && earliest {
+            self.failure = Some((part, status, error.unwrap_or_default()));
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let header = &self.header;
+        match (self.partial, self.failure) {
+            (Partial::Acks, None) | (Partial::Batch { .. }, None) => {
+                return dispatcher::reply(header, TsAck::ok())
+            }
+            (Partial::Acks, Some((_, status, error)))
+            | (Partial::Batch { .. }, Some((_, status, error))) => {
+                return dispatcher::reply(header, TsAck::error(status, error))
+            }
+            // Each shard sorts its own series, they're sorted again once together
+            (Partial::Series(mut series), None) => {
+                series.sort_by(|a, b| a.name.cmp(&b.name));
+                return dispatcher::reply(header, TsSelectReply::series(series));
+            }
+            (Partial::Series(_), Some((_, status, error))) => {
+                return dispatcher::reply(header, TsSelectReply::error(status, error))
+            }
+            (Partial::Groups(query, parts), None) => {
+                let groups = parts.into_values().collect();
+                return dispatcher::reply(header, dispatcher::combine_shards(&query, groups));
+            }
+            (Partial::Groups(..), Some((_, status, error))) => {
+                return dispatcher::reply(header, TsAggregateReply::error(status, error))
+            }
+        }
+    }
+}
+
+// The task a worker is running, with the outcome to send if it panics
+type Running = (Token, u64, usize, fn() -> Outcome);
+
+// The owner of a shard, running the jobs handed to it in order on a thread of its own and
+// waking up the server as their outcomes are ready
+struct Worker {
+    keyspace: Keyspace,
+    done: Sender<Done>,
+    waker: Arc<Waker>,
+}
+
+impl Worker {
+    // Run jobs till the server drops their sender, sweeping expired points and saving snapshots
+    // of the shard in between, then close the shard. Should the worker panic, the job it was
+    // running and every one handed to it afterwards fail instead, as their clients would otherwise
+    // wait for their responses forever.
+    fn run(mut self, jobs: Receiver<Task>) -> Result<(), Error> {
+        let mut current = None;
+        let served = panic::catch_unwind(AssertUnwindSafe(|| self.serve(&jobs, &mut current)));
+        if served.is_ok() {
+            return self.keyspace.close();
+        }
+        error!("Shard worker panicked, its requests fail from now on");
+        if let Some((token, seq, part, failure)) = current {
+            self.send(token, seq, part, failure());
+        }
+        for task in jobs.iter() {
+            self.send(task.token, task.seq, task.part, task.job.failure()());
+        }
+        return Err(Error::other("worker panicked"));
+    }
+
+    fn serve(&mut self, jobs: &Receiver<Task>, current: &mut Option<Running>) {
+        let mut last_sweep = Instant::now();
+        let mut last_snapshot = Instant::now();
+        loop {
+            match jobs.recv_timeout(SWEEP_INTERVAL.saturating_sub(last_sweep.elapsed())) {
+                Ok(task) => {
+                    *current = Some((task.token, task.seq, task.part, task.job.failure()));
+                    self.work(task);
+                    *current = None;
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            if last_sweep.elapsed() >= SWEEP_INTERVAL {
+                self.keyspace.expire(timeseries::now());
+                if let Err(e) = self.keyspace.tick() {
+                    warn!("Cannot sync the write-ahead log: {}", e);
+                }
+                last_sweep = Instant::now();
+            }
+            if last_snapshot.elapsed() >= SNAPSHOT_INTERVAL {
+                if let Err(e) = self.keyspace.snapshot() {
+                    warn!("Cannot save snapshot: {}", e);
+                }
+                last_snapshot = Instant::now();
+            }
+        }
+    }
+
+    fn work(&mut self, task: Task) {
+        let outcome = match task.job {
+            Job::Request(frame) => {
+                let response = dispatcher::dispatch(&mut self.keyspace, &frame);
+                Outcome::Response(Response::Frame(response))
+            }
+            Job::Stream(mut stream) => {
+                let frame = stream.next_frame(&self.keyspace).unwrap_or_default();
+                let rest = if stream.is_over() {
+                    None
+                } else {
+                    Some(Box::new(stream))
+                };
+                Outcome::Response(Response::Stream(frame, rest))
+            }
+            Job::Prepare(batch) => {
+                Outcome::Prepared(dispatcher::prepare_batch(&self.keyspace, &batch))
+            }
+            Job::Commit(points) => {
+                Outcome::Ack(dispatcher::commit_batch(&mut self.keyspace, points))
+            }
+            Job::Snapshot => Outcome::Ack(dispatcher::snapshot_shard(&mut self.keyspace)),
+            Job::Select(query) => Outcome::Series(dispatcher::select_shard(&self.keyspace, &query)),
+            Job::Aggregate(query) => {
+                Outcome::Groups(dispatcher::aggregate_shard(&self.keyspace, &query))
+            }
+            #[cfg(test)]
+            Job::Panic => panic!("worker told to panic"),
+        };
+        self.send(task.token, task.seq, task.part, outcome);
+    }
+
+    fn send(&self, token: Token, seq: u64, part: usize, outcome: Outcome) {
+        let done = Done {
+            token,
+            seq,
+            part,
+            outcome,
+        };
+        // The server stops listening only once it's not waiting for any outcome anymore
+        if self.done.send(done).is_ok() {
+            if let Err(e) = self.waker.wake() {
+                warn!("Cannot wake up the server: {}", e);
+            }
+        }
+    }
+}
+
+// The workers owning the shards of the keyspace, requests are routed to the shards owning the
+// series they're about, or split across every shard involved. Responses are collected once the
+// waker tells the server some are ready. The requests a client sends after a batch split across
+// shards are held until the batch is stored, as they could otherwise run before its commit.
+pub struct Shards {
+    jobs: Vec<Sender<Task>>,
+    done: Receiver<Done>,
+    workers: Vec<JoinHandle<Result<(), Error>>>,
+    merges: HashMap<(Token, u64), Merge>,
+    held: HashMap<Token, VecDeque<(u64, Vec<u8>)>>,
+    // Outcomes of the jobs that couldn't be handed to their worker, collected along with the
+    // others
+    failed: VecDeque<Done>,
+    waker: Arc<Waker>,
+}
+
+impl Shards {
+    pub fn start(keyspaces: Vec<Keyspace>, waker: Arc<Waker>) -> Result<Shards, Error> {
+        let (done, outcomes) = mpsc::channel();
+        let mut jobs = Vec::new();
+        let mut workers = Vec::new();
+        for (i, keyspace) in keyspaces.into_iter().enumerate() {
+            let (sender, receiver) = mpsc::channel();
+            let worker = Worker {
+                keyspace,
+                done: done.clone(),
+                waker: Arc::clone(&waker),
+            };
+            let handle = thread::Builder::new()
+                .name(format!("shard-{}", i))
+                .spawn(move || worker.run(receiver))?;
+            jobs.push(sender);
+            workers.push(handle);
+        }
+        return Ok(Shards {
+            jobs,
+            done: outcomes,
+            workers,
+            merges: HashMap::new(),
+            held: HashMap::new(),
+            failed: VecDeque::new(),
+            waker,
+        });
+    }
+
+    // Hand a request to the shards, its response is collected later tagged with the client token
+    // and the sequence number. Malformed requests or unknown commands go to the first shard, which
+    // replies with the error.
+    pub fn submit(&mut self, token: Token, seq: u64, frame: &[u8]) {
+        if let Some(held) = self.held.get_mut(&token) {
+            return held.push_back((seq, frame.to_vec()));
+        }
+        let shards = self.jobs.len();
+        let header = match TsHeader::from_binary(frame) {
+            Ok(header) if shards > 1 => header,
+            _ => return self.send(0, token, seq, 0, Job::Request(frame.to_vec())),
+        };
+        match header.opcode() {
+            Some(OpCode::OpTsMaddPoint) => {
+                if let Some(batch) = decode::<TsMaddPoint>(frame) {
+                    let parts = split(batch, shards);
+                    if parts.len() > 1 {
+                        return self.submit_batch(header, token, seq, parts);
+                    }
+                    let shard = parts.first().map_or(0, |(shard, _)| *shard);
+                    return self.send(shard, token, seq, 0, Job::Request(frame.to_vec()));
+                }
+            }
+            Some(OpCode::OpTsSnapshot) if decode::<TsSnapshot>(frame).is_some() => {
+                return self.fan_out(header, token, seq, Partial::Acks, || Job::Snapshot);
+            }
+            Some(OpCode::OpTsSelect) => {
+                if let Some(query) = decode::<TsSelect>(frame) {
+                    let partial = Partial::Series(Vec::new());
+                    return self.fan_out(header, token, seq, partial, || {
+                        return Job::Select(query.clone());
+                    });
+                }
+            }
+            Some(OpCode::OpTsAggregate) => {
+                if let Some(query) = decode::<TsAggregate>(frame) {
+                    let partial = Partial::Groups(query.clone(), BTreeMap::new());
+                    return self.fan_out(header, token, seq, partial, || {
+                        return Job::Aggregate(query.clone());
+                    });
+                }
+            }
+            _ => (),
+        }
+        let shard = series_name(header.opcode(), frame).map_or(0, |n| shard_of(&n, shards));
+        self.send(shard, token, seq, 0, Job::Request(frame.to_vec()));
+    }
+
+    // Hand the next frame of a stream to produce to the shard owning the series
+    pub fn resume(&mut self, token: Token, seq: u64, stream: Stream) {
+        let shard = shard_of(stream.name(), self.jobs.len());
+        self.send(shard, token, seq, 0, Job::Stream(stream));
+    }
+
+    // The responses ready, the ones of requests split across shards once every part is done
+    pub fn completed(&mut self) -> Vec<(Token, u64, Response)> {
+        let mut responses = Vec::new();
+        while let Some(done) = self
+            .failed
+            .pop_front()
+            .or_else(|| self.done.try_recv().ok())
+        {
+            if let Some(response) = self.complete(done) {
+                responses.push(response);
+            }
+        }
+        return responses;
+    }
+
+    // Stop the workers once they've run every job already handed to them, waiting for the
+    // batches split across shards to be either stored or refused. Requests held behind them are
+    // dropped.
+    pub fn stop(mut self) -> Result<(), Error> {
+        self.held.clear();
+        while !self.merges.is_empty() {
+            match self.failed.pop_front().map_or_else(|| self.done.recv(), Ok) {
+                Ok(done) => self.complete(done),
+                Err(_) => break,
+            };
+        }
+        self.jobs.clear();
+        while let Ok(done) = self.done.recv() {
+            self.complete(done);
+        }
+        let mut result = Ok(());
+        for worker in self.workers {
+            let closed = worker.join().unwrap_or_else(|_| {
+                return Err(Error::other("worker panicked"));
+            });
+            if result.is_ok() {
+                result = closed;
+            }
+        }
+        return result;
+    }
+
+    fn send(&mut self, shard: usize, token: Token, seq: u64, part: usize, job: Job) {
+        let task = Task {
+            token,
+            seq,
+            part,
+            job,
+        };
+        // Workers only stop once their sender is dropped, unless the thread is gone after a panic
+        if let Err(mpsc::SendError(task)) = self.jobs[shard].send(task) {
+            warn!("Shard {} is unavailable, its worker stopped", shard);
+            self.failed.push_back(Done {
+                token,
+                seq,
+                part,
+                outcome: task.job.failure()(),
+            });
+            if let Err(e) = self.waker.wake() {
+                warn!("Cannot wake up the server: {}", e);
+            }
+        }
+    }
+
+    fn submit_batch(
+        &mut self,
+        header: TsHeader,
+        token: Token,
+        seq: u64,
+        parts: Vec<(usize, TsMaddPoint)>,
+    ) {
+        let count = parts.len();
+        let mut shards = Vec::new();
+        for (part, (shard, batch)) in parts.into_iter().enumerate() {
+            shards.push(shard);
+            self.send(shard, token, seq, part, Job::Prepare(batch));
+        }
+        let partial = Partial::Batch {
+            shards,
+            prepared: vec![Vec::new(); count],
+            committing: false,
+        };
+        self.merges
+            .insert((token, seq), Merge::new(header, count, partial));
+        self.held.insert(token, VecDeque::new());
+    }
+
+    fn fan_out<F>(&mut self, header: TsHeader, token: Token, seq: u64, partial: Partial, job: F)
+    where
+        F: Fn() -> Job,
+    {
+        for shard in 0..self.jobs.len() {
+            self.send(shard, token, seq, shard, job());
+        }
+        let merge = Merge::new(header, self.jobs.len(), partial);
+        self.merges.insert((token, seq), merge);
+    }
+
+    fn complete(&mut self, done: Done) -> Option<(Token, u64, Response)> {
+        let key = (done.token, done.seq);
+        let merge = match (self.merges.get_mut(&key), done.outcome) {
+            (Some(merge), outcome) => {
+                merge.add(done.part, outcome);
+                merge
+            }
+            (None, Outcome::Response(response)) => return Some((done.token, done.seq, response)),
+            (None, _) => return None,
+        };
+        if merge.pending > 0 {
+            return None;
+        }
+        if let Partial::Batch {
+            shards,
+            prepared,
+            committing,
+        } = &mut merge.partial
+        {
+            if !*committing && merge.failure.is_none() {
+                *committing = true;
+                merge.pending = shards.len();
+                let commits: Vec<(usize, Vec<(String, Record)>)> =
+                    shards.iter().copied().zip(prepared.drain(..)).collect();
+                for (part, (shard, points)) in commits.into_iter().enumerate() {
+                    self.send(shard, done.token, done.seq, part, Job::Commit(points));
+                }
+                return None;
+            }
+            self.release(done.token);
+        }
+        let merge = self.merges.remove(&key)?;
+        return Some((done.token, done.seq, Response::Frame(merge.finish())));
+    }
+
+    // Submit the requests held behind a batch once it's over, till the next batch among them
+    fn release(&mut self, token: Token) {
+        if let Some(held) = self.held.remove(&token) {
+            for (seq, frame) in held {
+                self.submit(token, seq, &frame);
+            }
+        }
+    }
+}
+
+// Split a batch in the parts of the shards owning its points, in order of their first point
+fn split(batch: TsMaddPoint, shards: usize) -> Vec<(usize, TsMaddPoint)> {
+    let mut parts: Vec<(usize, TsMaddPoint)> = Vec::new();
+    for point in batch.points {
+        let shard = shard_of(&point.name, shards);
+        match parts.iter_mut().find(|(s, _)| *s == shard) {
+            Some((_, part)) => part.points.push(point),
+            None => parts.push((
+                shard,
+                TsMaddPoint {
+                    points: vec![point],
+                },
+            )),
+        }
+    }
+    return parts;
+}
+
+// The series a request is about, if it's about a single one
+fn series_name(opcode: Option<OpCode>, frame: &[u8]) -> Option<String> {
+    match opcode? {
+        OpCode::OpTsCreate => return decode::<TsCreate>(frame).map(|p| p.name),
+        OpCode::OpTsDelete => return decode::<TsDelete>(frame).map(|p| p.name),
+        OpCode::OpTsAddPoint => return decode::<TsAddPoint>(frame).map(|p| p.name),
+        OpCode::OpTsQuery => return decode::<TsQuery>(frame).map(|p| p.name),
+        OpCode::OpTsInfo => return decode::<TsInfo>(frame).map(|p| p.name),
+        OpCode::OpTsRate => return decode::<TsRate>(frame).map(|p| p.name),
+        _ => return None,
+    }
+}
+
+fn decode<T>(frame: &[u8]) -> Option<T>
+where
+    T: Serialize,
+    T: DeserializeOwned,
+{
+    return TsPacket::<T>::from_binary(frame)
+        .ok()
+        .map(|p| p.into_packet());
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::aggregate;
+    use crate::index::{Labels, MatchOp, Matcher, NAME_LABEL};
+    use crate::timeseries::{Aggregator, FillPolicy, Record, TimeSeries};
+    use mio::Poll;
+    use std::collections::BTreeMap;
+
+    fn request<T>(opcode: OpCode, packet: T) -> Vec<u8>
+    where
+        T: Serialize,
+        T: DeserializeOwned,
+    {
+        return TsPacket::new(opcode, packet).to_binary().unwrap();
+    }
+
+    fn add_point(name: &str, timestamp: u128, value: f64) -> TsAddPoint {
+        return TsAddPoint {
+            name: name.to_string(),
+            timestamp: Some(timestamp),
+            value,
+        };
+    }
+
+    // Submit the requests and wait for their responses, in order
+    fn run(shards: &mut Shards, requests: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        for (seq, frame) in requests.iter().enumerate() {
+            shards.submit(Token(1), seq as u64, frame);
+        }
+        let mut responses = BTreeMap::new();
+        while responses.len() < requests.len() {
+            for (_, seq, response) in shards.completed() {
+                if let Response::Frame(frame) = response {
+                    responses.insert(seq, frame);
+                }
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        return responses.into_values().collect();
+    }
+
+    fn packet<T>(frame: &[u8]) -> T
+    where
+        T: Serialize,
+        T: DeserializeOwned,
+    {
+        return TsPacket::from_binary(frame).unwrap().into_packet();
+    }
+
+    fn data_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("teaspoon-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        return dir;
+    }
+
+    #[test]
+    fn test_shard_of() {
+        let names: Vec<String> = (0..1000).map(|i| format!("cpu-{}", i)).collect();
+        let mut counts = [0; 4];
+        for name in &names {
+            assert_eq!(shard_of(name, 1), 0);
+            assert_eq!(shard_of(name, 4), shard_of(name, 4));
+            counts[shard_of(name, 4)] += 1;
+        }
+        assert!(counts.iter().all(|c| *c > 150), "{:?}", counts);
+        // Persisted series rely on the hash staying the same
+        assert_eq!(shard_of("", 1 << 20), 0xcbf2_9ce4_8422_2325 % (1 << 20));
+        assert_eq!(shard_of("a", 1 << 20), 0xaf63_dc4c_8601_ec8c % (1 << 20));
+    }
+
+    #[test]
+    fn test_split() {
+        let names = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        let batch = TsMaddPoint {
+            points: names.iter().map(|n| add_point(n, 10, 1.0)).collect(),
+        };
+        let parts = split(batch, 3);
+        let mut shards: Vec<usize> = parts.iter().map(|(s, _)| *s).collect();
+        assert_eq!(shards[0], shard_of("a", 3));
+        shards.dedup();
+        assert_eq!(shards.len(), parts.len());
+        let mut split_names = Vec::new();
+        for (shard, part) in &parts {
+            for point in &part.points {
+                assert_eq!(shard_of(&point.name, 3), *shard);
+                split_names.push(point.name.as_str());
+            }
+        }
+        split_names.sort_unstable();
+        assert_eq!(split_names, names);
+        assert!(split(TsMaddPoint { points: Vec::new() }, 3).is_empty());
+    }
+
+    #[test]
+    fn test_open() {
+        let dir = data_dir("shard-open");
+        let mut keyspaces = open(&dir, 4, FsyncPolicy::Never).unwrap();
+        assert_eq!(keyspaces.len(), 4);
+        assert!(dir.join("shard-3").is_dir());
+        for keyspace in keyspaces.iter_mut() {
+            keyspace.snapshot().unwrap();
+        }
+        assert_eq!(open(&dir, 4, FsyncPolicy::Never).unwrap().len(), 4);
+        // The number of shards can't change, nor the data of a single one be sharded
+        assert!(open(&dir, 2, FsyncPolicy::Never).is_err());
+        assert!(open(&dir, 1, FsyncPolicy::Never).is_err());
+        let single = dir.join("shard-0");
+        assert_eq!(open(&single, 1, FsyncPolicy::Never).unwrap().len(), 1);
+        assert!(open(&single, 2, FsyncPolicy::Never).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_groups() {
+        let query = TsAggregate {
+            matchers: Vec::new(),
+            range: None,
+            aggregation: Aggregator::Sum,
+            interval: 100,
+            group_by: Vec::new(),
+            fill: FillPolicy::None,
+        };
+        let frame = request(OpCode::OpTsAggregate, query.clone());
+        // Sums of floats depend on their order, shards are merged in the same one nonetheless
+        let parts: Vec<Groups> = [0.1, 0.2, 0.3]
+            .iter()
+            .map(|value| {
+                let mut ts = TimeSeries::new("cpu".to_string(), None, None);
+                ts.add_point(Record::with_timestamp(10, *value));
+                let mut groups = Groups::new();
+                let buckets = groups.entry(Labels::new()).or_default();
+                aggregate::collect(&[&ts], None, 100, Aggregator::Sum, buckets);
+                return groups;
+            })
+            .collect();
+        let merged = |order: &[usize]| -> TsAggregateReply {
+            let partial = Partial::Groups(query.clone(), BTreeMap::new());
+            let header = TsHeader::from_binary(&frame).unwrap();
+            let mut merge = Merge::new(header, order.len(), partial);
+            for part in order {
+                merge.add(*part, Outcome::Groups(Ok(parts[*part].clone())));
+            }
+            return packet(&merge.finish());
+        };
+        let reply = merged(&[0, 1, 2]);
+        assert_eq!(reply.groups[0].points, vec![(0, Some(0.1 + 0.2 + 0.3))]);
+        for order in &[[2, 1, 0], [1, 2, 0], [2, 0, 1]] {
+            assert_eq!(merged(order).groups, reply.groups);
+        }
+    }
+
+    fn start(keyspaces: Vec<Keyspace>) -> (Poll, Shards) {
+        let poll = Poll::new().unwrap();
+        let waker = Arc::new(Waker::new(poll.registry(), Token(0)).unwrap());
+        return (poll, Shards::start(keyspaces, waker).unwrap());
+    }
+
+    const NAMES: [&str; 6] = ["cpu-1", "cpu-2", "cpu-3", "cpu-4", "cpu-5", "cpu-6"];
+
+    // Create every series, on hosts web-0 and web-1 in turn
+    fn create_all(shards: &mut Shards) {
+        let creates: Vec<Vec<u8>> = NAMES
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let mut labels = Labels::new();
+                labels.insert("host".to_string(), format!("web-{}", i % 2));
+                let create = TsCreate {
+                    name: name.to_string(),
+                    labels,
+                    retention: None,
+                    duplicate_policy: None,
+                };
+                return request(OpCode::OpTsCreate, create);
+            })
+            .collect();
+        for response in run(shards, &creates) {
+            assert_eq!(packet::<TsAck>(&response), TsAck::ok());
+        }
+    }
+
+    fn batch(timestamp: u128) -> TsMaddPoint {
+        return TsMaddPoint {
+            points: NAMES.iter().map(|n| add_point(n, timestamp, 2.0)).collect(),
+        };
+    }
+
+    fn select_all() -> Vec<u8> {
+        let select = TsSelect {
+            matchers: vec![Matcher::new(NAME_LABEL, MatchOp::Re, "cpu-.*")],
+            range: None,
+        };
+        return request(OpCode::OpTsSelect, select);
+    }
+
+    // Number of points of every series, in name order
+    fn lengths(response: &[u8]) -> Vec<usize> {
+        let reply: TsSelectReply = packet(response);
+        return reply.series.iter().map(|s| s.records.len()).collect();
+    }
+
+    #[test]
+    fn test_shards_routing() {
+        let dir = data_dir("shard-routing");
+        let (_poll, mut shards) = start(open(&dir, 4, FsyncPolicy::Never).unwrap());
+        create_all(&mut shards);
+        let adds: Vec<Vec<u8>> = NAMES
+            .iter()
+            .map(|n| request(OpCode::OpTsAddPoint, add_point(n, 10, 1.0)))
+            .collect();
+        for response in run(&mut shards, &adds) {
+            assert_eq!(packet::<TsAck>(&response), TsAck::ok());
+        }
+        shards.stop().unwrap();
+        // Every series lives in the shard its name hashes to, and only there
+        for i in 0..4 {
+            let keyspace = Keyspace::open(&dir.join(format!("shard-{}", i)), FsyncPolicy::Never);
+            let keyspace = keyspace.unwrap();
+            for name in &NAMES {
+                let owned = shard_of(name, 4) == i;
+                assert_eq!(keyspace.get(name).map(|ts| ts.len()), owned.then_some(1));
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_shards_batch() {
+        let (_poll, mut shards) = start((0..4).map(|_| Keyspace::new()).collect());
+        create_all(&mut shards);
+        // Requests following a batch see all of its points
+        let responses = run(
+            &mut shards,
+            &[request(OpCode::OpTsMaddPoint, batch(10)), select_all()],
+        );
+        assert_eq!(packet::<TsAck>(&responses[0]), TsAck::ok());
+        assert_eq!(lengths(&responses[1]), vec![1; 6]);
+        // A batch with an invalid part is stored by none of the shards
+        let mut invalid = batch(20);
+        invalid.points.push(add_point("cpu-0", 20, 4.0));
+        let responses = run(
+            &mut shards,
+            &[request(OpCode::OpTsMaddPoint, invalid), select_all()],
+        );
+        let ack: TsAck = packet(&responses[0]);
+        assert_eq!(ack.status, Status::TsNotFount);
+        assert_eq!(ack.error, Some("Series cpu-0 not found".to_string()));
+        assert_eq!(lengths(&responses[1]), vec![1; 6]);
+        shards.stop().unwrap();
+    }
+
+    #[test]
+    fn test_shards_batch_partial() {
+        // Validation passes everywhere but storing fails on the shard of cpu-1
+        let broken = shard_of("cpu-1", 4);
+        let keyspaces = (0..4)
+            .map(|i| {
+                let mut keyspace = Keyspace::new();
+                for name in NAMES.iter().filter(|n| shard_of(n, 4) == i) {
+                    keyspace
+                        .create(name.to_string(), Labels::new(), None, None)
+                        .unwrap();
+                }
+                if i == broken {
+                    keyspace.break_wal();
+                }
+                return keyspace;
+            })
+            .collect();
+        let (_poll, mut shards) = start(keyspaces);
+        let responses = run(
+            &mut shards,
+            &[request(OpCode::OpTsMaddPoint, batch(10)), select_all()],
+        );
+        let ack: TsAck = packet(&responses[0]);
+        assert_eq!(ack.status, Status::TsServerError);
+        assert!(ack.error.unwrap().starts_with("Write-ahead log error"));
+        // The other parts are stored nonetheless
+        let expected: Vec<usize> = NAMES
+            .iter()
+            .map(|n| if shard_of(n, 4) == broken { 0 } else { 1 })
+            .collect();
+        assert!(expected.contains(&1));
+        assert_eq!(lengths(&responses[1]), expected);
+        shards.stop().unwrap();
+    }
+
+    #[test]
+    fn test_shards_fan_out() {
+        let (_poll, mut shards) = start((0..4).map(|_| Keyspace::new()).collect());
+        create_all(&mut shards);
+        run(&mut shards, &[request(OpCode::OpTsMaddPoint, batch(10))]);
+        let aggregate = TsAggregate {
+            matchers: vec![Matcher::new(NAME_LABEL, MatchOp::Re, "cpu-.*")],
+            range: None,
+            aggregation: Aggregator::Count,
+            interval: 100,
+            group_by: vec!["host".to_string()],
+            fill: FillPolicy::None,
+        };
+        let bad_select = TsSelect {
+            matchers: Vec::new(),
+            range: None,
+        };
+        let responses = run(
+            &mut shards,
+            &[
+                select_all(),
+                request(OpCode::OpTsAggregate, aggregate),
+                request(OpCode::OpTsSelect, bad_select),
+                request(OpCode::OpTsSnapshot, TsSnapshot),
+            ],
+        );
+        // Series of every shard are sorted once together
+        let reply: TsSelectReply = packet(&responses[0]);
+        let selected: Vec<&str> = reply.series.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(selected, NAMES);
+        // Groups spanning shards add up their points
+        let reply: TsAggregateReply = packet(&responses[1]);
+        assert_eq!(reply.groups.len(), 2);
+        assert!(reply
+            .groups
+            .iter()
+            .all(|g| g.points == vec![(0, Some(3.0))]));
+        assert_eq!(
+            packet::<TsSelectReply>(&responses[2]).status,
+            Status::TsBadRequest
+        );
+        // Shards living in memory only can't be snapshotted
+        let ack: TsAck = packet(&responses[3]);
+        assert_eq!(ack.status, Status::TsServerError);
+        shards.stop().unwrap();
+    }
+
+    #[test]
+    fn test_shards_unavailable() {
+        let (_poll, mut shards) = start((0..4).map(|_| Keyspace::new()).collect());
+        create_all(&mut shards);
+        // The job a worker panics on fails, as does every one handed to it afterwards
+        let panicked = shard_of("cpu-1", 4);
+        shards.send(panicked, Token(2), 0, 0, Job::Panic);
+        let mut failed = Vec::new();
+        while failed.is_empty() {
+            failed = shards.completed();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(matches!(&failed[..], [(Token(2), 0, Response::Frame(_))]));
+        // So does every job of a worker whose thread is gone
+        let gone = (0..4).find(|s| *s != panicked).unwrap();
+        let name = NAMES.iter().find(|n| shard_of(n, 4) == gone).unwrap();
+        shards.jobs[gone] = mpsc::channel().0;
+        let available = NAMES
+            .iter()
+            .find(|n| shard_of(n, 4) != panicked && shard_of(n, 4) != gone)
+            .unwrap();
+        let responses = run(
+            &mut shards,
+            &[
+                request(OpCode::OpTsAddPoint, add_point("cpu-1", 10, 1.0)),
+                request(OpCode::OpTsAddPoint, add_point(name, 10, 1.0)),
+                request(OpCode::OpTsMaddPoint, batch(10)),
+                select_all(),
+                request(OpCode::OpTsAddPoint, add_point(available, 10, 1.0)),
+            ],
+        );
+        for response in &responses[..3] {
+            let ack: TsAck = packet(response);
+            assert_eq!(ack.status, Status::TsServerError);
+        }
+        let reply: TsSelectReply = packet(&responses[3]);
+        assert_eq!(reply.status, Status::TsServerError);
+        assert_eq!(packet::<TsAck>(&responses[4]), TsAck::ok());
+        assert!(shards.stop().is_err());
+    }
+}
@@ -0,0 +1,207 @@
+// BSD 2-Clause License
+//
+// Copyright (c) 2020, Andrea Giacomo Baldan
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+//   list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+//   this list of conditions and the following disclaimer in the documentation
+//   and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// Relative accuracy of the quantiles estimated by a sketch
+const ALPHA: f64 = 0.01;
+
+// Values closer to zero than this are all counted as zeros
+const MIN_VALUE: f64 = 1e-9;
+
+// Mergeable quantile sketch, as described in the DDSketch paper: values are counted in buckets of
+// exponentially growing size, so every quantile is estimated with a relative error within ALPHA.
+// Merging two sketches is just adding up their buckets, it's the same as having added all the
+// values to a single one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Sketch {
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+    zeros: u64,
+    count: u64,
+    min: f64,
+    max: f64,
+}
+
+impl Default for Sketch {
+    fn default() -> Sketch {
+        return Sketch::new();
+    }
+}
+
+impl Sketch {
+    pub fn new() -> Sketch {
+        Sketch {
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zeros: 0,
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    // Add a value, NaNs are ignored as they can't be ordered
+    pub fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        if value > MIN_VALUE {
+            *self.positive.entry(key(value)).or_insert(0) += 1;
+        } else if value < -MIN_VALUE {
+            *self.negative.entry(key(-value)).or_insert(0) += 1;
+        } else {
+            self.zeros += 1;
+        }
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn merge(&mut self, other: &Sketch) {
+        for (k, n) in &other.positive {
+            *self.positive.entry(*k).or_insert(0) += n;
+        }
+        for (k, n) in &other.negative {
+            *self.negative.entry(*k).or_insert(0) += n;
+        }
+        self.zeros += other.zeros;
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    // Estimate the q quantile, with q between 0 and 1, None if the sketch is empty
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (q * (self.count - 1) as f64).round() as u64;
+        // Extremes are known exactly
+        if rank == 0 {
+            return Some(self.min);
+        }
+        if rank >= self.count - 1 {
+            return Some(self.max);
+        }
+        let mut seen = 0;
+        // Negative values in ascending order come from the largest magnitudes
+        for (k, n) in self.negative.iter().rev() {
+            seen += n;
+            if seen > rank {
+                return Some(clamp(-value(*k), self.min, self.max));
+            }
+        }
+        seen += self.zeros;
+        if seen > rank {
+            return Some(0.0);
+        }
+        for (k, n) in &self.positive {
+            seen += n;
+            if seen > rank {
+                return Some(clamp(value(*k), self.min, self.max));
+            }
+        }
+        return Some(self.max);
+    }
+}
+
+fn gamma() -> f64 {
+    return (1.0 + ALPHA) / (1.0 - ALPHA);
+}
+
+// Index of the bucket counting a positive value
+fn key(value: f64) -> i32 {
+    return (value.ln() / gamma().ln()).ceil() as i32;
+}
+
+// Value representing a bucket, within the relative accuracy of every value it counts
+fn value(key: i32) -> f64 {
+    return 2.0 * gamma().powi(key) / (gamma() + 1.0);
+}
+
+fn clamp(value: f64, min: f64, max: f64) -> f64 {
+    return value.max(min).min(max);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn assert_close(estimate: f64, exact: f64) {
+        assert!(
+            (estimate - exact).abs() <= exact.abs() * ALPHA,
+            "{} is not within {} of {}",
+            estimate,
+            ALPHA,
+            exact
+        );
+    }
+
+    #[test]
+    fn test_sketch_quantile() {
+        let mut sketch = Sketch::new();
+        for i in 1..=10_000 {
+            sketch.add(i as f64);
+        }
+        assert_close(sketch.quantile(0.5).unwrap(), 5001.0);
+        assert_close(sketch.quantile(0.95).unwrap(), 9500.0);
+        assert_close(sketch.quantile(0.99).unwrap(), 9900.0);
+        assert_eq!(sketch.quantile(0.0), Some(1.0));
+        assert_eq!(sketch.quantile(1.0), Some(10_000.0));
+        assert_eq!(Sketch::new().quantile(0.5), None);
+    }
+
+    #[test]
+    fn test_sketch_negative_values() {
+        let mut sketch = Sketch::new();
+        for i in -500..500 {
+            sketch.add(i as f64);
+        }
+        sketch.add(f64::NAN);
+        assert_eq!(sketch.count, 1000);
+        assert_close(sketch.quantile(0.1).unwrap(), -400.0);
+        assert_eq!(sketch.quantile(0.5), Some(0.0));
+        assert_close(sketch.quantile(0.9).unwrap(), 399.0);
+    }
+
+    #[test]
+    fn test_sketch_merge() {
+        let mut a = Sketch::new();
+        let mut b = Sketch::new();
+        let mut all = Sketch::new();
+        for i in 0..1000 {
+            a.add(i as f64);
+            b.add((i * 3) as f64);
+            all.add(i as f64);
+            all.add((i * 3) as f64);
+        }
+        a.merge(&b);
+        assert_eq!(a, all);
+    }
+}
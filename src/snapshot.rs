@@ -0,0 +1,154 @@
+// BSD 2-Clause License
+//
+// Copyright (c) 2020, Andrea Giacomo Baldan
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+//   list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+//   this list of conditions and the following disclaimer in the documentation
+//   and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::timeseries::TimeSeries;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+// Every snapshot starts with the magic bytes followed by the version of its format, as a little
+// endian u32
+const MAGIC: &[u8; 4] = b"TSPS";
+const VERSION: u32 = 1;
+
+// A point-in-time copy of the whole keyspace, along with the sequence number of the last
+// write-ahead log entry it includes
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    lsn: u64,
+    series: Vec<&'a TimeSeries>,
+}
+
+#[derive(Deserialize)]
+pub struct Snapshot {
+    pub lsn: u64,
+    pub series: Vec<TimeSeries>,
+}
+
+// Write the snapshot atomically: it's first written to a temporary file which is then renamed
+// over the previous snapshot, a crash in the middle never leaves a partial one behind. The
+// directory is synced as well before returning, the rename must be durable before the write-ahead
+// log entries the snapshot includes can be dropped.
+pub fn save(path: &Path, lsn: u64, series: Vec<&TimeSeries>) -> Result<(), Error> {
+    let tmp = path.with_extension("tmp");
+    let file = File::create(&tmp)?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    bincode::serialize_into(&mut writer, &SnapshotRef { lsn, series })
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp, path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    return Ok(());
+}
+
+// Read the snapshot at path, None if there's none yet
+pub fn load(path: &Path) -> Result<Option<Snapshot>, Error> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut reader = BufReader::new(file);
+    let mut header = [0; 8];
+    let valid = match reader.read_exact(&mut header) {
+        Ok(()) => &header[..4] == MAGIC,
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e),
+    };
+    if !valid {
+        return Err(Error::new(ErrorKind::InvalidData, "not a snapshot"));
+    }
+    let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if version != VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported snapshot version {}", version),
+        ));
+    }
+    let snapshot =
+        bincode::deserialize_from(reader).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    return Ok(Some(snapshot));
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::timeseries::{Record, Retention};
+
+    #[test]
+    fn test_snapshot_save_load() {
+        let path = std::env::temp_dir().join(format!("teaspoon-{}.snapshot", std::process::id()));
+        assert!(load(&path).unwrap().is_none());
+        let mut ts = TimeSeries::new("test-ts".to_string(), Some(Retention::Count(10)), None);
+        ts.add_point(Record::with_timestamp(10, 12.98));
+        ts.add_point(Record::with_timestamp(20, 19.63));
+        save(&path, 42, vec![&ts]).unwrap();
+        let snapshot = load(&path).unwrap().unwrap();
+        assert_eq!(snapshot.lsn, 42);
+        assert_eq!(snapshot.series.len(), 1);
+        assert_eq!(snapshot.series[0].len(), 2);
+        assert_eq!(snapshot.series[0].iter().nth(1).unwrap().timestamp(), 20);
+        assert!(!path.with_extension("tmp").exists());
+        assert_eq!(&fs::read(&path).unwrap()[..8], b"TSPS\x01\x00\x00\x00");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_versions() {
+        let path =
+            std::env::temp_dir().join(format!("teaspoon-versions-{}.snapshot", std::process::id()));
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, None);
+        ts.add_point(Record::with_timestamp(10, 12.98));
+        let body = bincode::serialize(&SnapshotRef {
+            lsn: 7,
+            series: vec![&ts],
+        })
+        .unwrap();
+        let mut current = b"TSPS\x01\x00\x00\x00".to_vec();
+        current.extend_from_slice(&body);
+        fs::write(&path, &current).unwrap();
+        let snapshot = load(&path).unwrap().unwrap();
+        assert_eq!(snapshot.lsn, 7);
+        assert_eq!(snapshot.series[0].len(), 1);
+        // Other formats are refused rather than misread, headerless ones included
+        for header in &[&b""[..], b"TSPS\x00\x00\x00\x00", b"TSPS\x02\x00\x00\x00"] {
+            let mut other = header.to_vec();
+            other.extend_from_slice(&body);
+            fs::write(&path, &other).unwrap();
+            assert_eq!(load(&path).err().unwrap().kind(), ErrorKind::InvalidData);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+// BSD 2-Clause License
+//
+// Copyright (c) 2020, Andrea Giacomo Baldan
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+//   list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+//   this list of conditions and the following disclaimer in the documentation
+//   and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::timeseries::Aggregator;
+use serde::{Deserialize, Serialize};
+
+// Summary of a sequence of values in time order, enough to compute the simple aggregations of the
+// whole sequence, and to combine with the summary of the sequence following it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub count: usize,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+    pub first: f64,
+    pub last: f64,
+}
+
+impl Summary {
+    pub fn new(value: f64) -> Summary {
+        Summary {
+            count: 1,
+            sum: value,
+            min: value,
+            max: value,
+            first: value,
+            last: value,
+        }
+    }
+
+    // Summary of a sequence of values, None if it's empty
+    pub fn of<I>(values: I) -> Option<Summary>
+    where
+        I: Iterator<Item = f64>,
+    {
+        return values.map(Summary::new).reduce(|s, v| s.merge(&v));
+    }
+
+    // Summary of this sequence followed by the later one
+    pub fn merge(&self, later: &Summary) -> Summary {
+        Summary {
+            count: self.count + later.count,
+            sum: self.sum + later.sum,
+            min: self.min.min(later.min),
+            max: self.max.max(later.max),
+            first: self.first,
+            last: later.last,
+        }
+    }
+
+    // Value of the aggregation over the summarized sequence, None if it can't be told from the
+    // summary alone
+    pub fn aggregate(&self, aggregator: Aggregator) -> Option<f64> {
+        match aggregator {
+            Aggregator::Avg => return Some(self.sum / self.count as f64),
+            Aggregator::Sum => return Some(self.sum),
+            Aggregator::Count => return Some(self.count as f64),
+            Aggregator::Min => return Some(self.min),
+            Aggregator::Max => return Some(self.max),
+            Aggregator::First => return Some(self.first),
+            Aggregator::Last => return Some(self.last),
+            Aggregator::Range => return Some(self.max - self.min),
+            _ => return None,
+        }
+    }
+}
+
+// Combine two optional summaries of consecutive sequences
+pub fn combine(earlier: Option<Summary>, later: Option<Summary>) -> Option<Summary> {
+    match (earlier, later) {
+        (Some(a), Some(b)) => return Some(a.merge(&b)),
+        (a, None) => return a,
+        (None, b) => return b,
+    }
+}
+
+// Segment tree over a sequence of summaries, combines any range of consecutive ones in O(log n).
+// Leaves are stored in the second half of the nodes, every other node combines its two children.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SummaryTree {
+    nodes: Vec<Option<Summary>>,
+}
+
+impl SummaryTree {
+    pub fn new(leaves: Vec<Summary>) -> SummaryTree {
+        let n = leaves.len();
+        let mut nodes = vec![None; n];
+        nodes.extend(leaves.into_iter().map(Some));
+        for i in (1..n).rev() {
+            nodes[i] = combine(nodes[2 * i], nodes[2 * i + 1]);
+        }
+        return SummaryTree { nodes };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.nodes.len() / 2;
+    }
+
+    pub fn update(&mut self, i: usize, summary: Summary) {
+        let mut i = i + self.len();
+        self.nodes[i] = Some(summary);
+        while i > 1 {
+            i /= 2;
+            self.nodes[i] = combine(self.nodes[2 * i], self.nodes[2 * i + 1]);
+        }
+    }
+
+    // Summary of the leaves in the [lo, hi) range, None if it's empty
+    pub fn query(&self, lo: usize, hi: usize) -> Option<Summary> {
+        let (mut lo, mut hi) = (lo + self.len(), hi + self.len());
+        // Summaries are not commutative, the left and right sides are combined separately
+        let mut left = None;
+        let mut right = None;
+        while lo < hi {
+            if lo % 2 == 1 {
+                left = combine(left, self.nodes[lo]);
+                lo += 1;
+            }
+            if hi % 2 == 1 {
+                hi -= 1;
+                right = combine(self.nodes[hi], right);
+            }
+            lo /= 2;
+            hi /= 2;
+        }
+        return combine(left, right);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let s = Summary::of(vec![3.0, 1.0, 4.0, 1.5].into_iter()).unwrap();
+        assert_eq!(s.count, 4);
+        assert_eq!(s.aggregate(Aggregator::Avg), Some(2.375));
+        assert_eq!(s.aggregate(Aggregator::First), Some(3.0));
+        assert_eq!(s.aggregate(Aggregator::Last), Some(1.5));
+        assert_eq!(s.aggregate(Aggregator::Range), Some(3.0));
+        assert_eq!(s.aggregate(Aggregator::Variance), None);
+        assert!(Summary::of(std::iter::empty()).is_none());
+    }
+
+    #[test]
+    fn test_summary_tree() {
+        let values: Vec<f64> = (0..13).map(|i| ((i * 7) % 13) as f64).collect();
+        let mut tree = SummaryTree::new(values.iter().map(|v| Summary::new(*v)).collect());
+        for lo in 0..values.len() {
+            for hi in lo..=values.len() {
+                let expected = Summary::of(values[lo..hi].iter().cloned());
+                assert_eq!(tree.query(lo, hi), expected);
+            }
+        }
+        tree.update(5, Summary::new(100.0));
+        let s = tree.query(2, 9).unwrap();
+        assert_eq!(s.max, 100.0);
+        assert_eq!(s.first, values[2]);
+        assert_eq!(s.last, values[8]);
+        assert!(SummaryTree::new(Vec::new()).query(0, 0).is_none());
+    }
+}
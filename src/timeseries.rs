@@ -24,16 +24,28 @@
 // OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use std::cmp::{Ordering, PartialEq};
-use std::ops::Index;
+use crate::aggregate;
+use crate::chunk::Chunk;
+use crate::index::Labels;
+use crate::sketch::Sketch;
+use crate::summary::{self, Summary, SummaryTree};
+use serde::{Deserialize, Serialize};
+use std::cmp::PartialEq;
+use std::ops::{Bound, RangeBounds};
 use std::option::Option;
-use std::thread::sleep;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-// A record of the timeseries, represents a point defined as a tuple (timestamp, value), as a
-// future improvement it will probably also contain some sorts of labels to be used as a secondary
-// indexes
-#[derive(Debug, Clone)]
+// Current time as milliseconds since the UNIX epoch
+pub fn now() -> u128 {
+    let ctime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Unable to get now");
+    return ctime.as_millis();
+}
+
+// A record of the timeseries, represents a point defined as a tuple (timestamp, value), labels
+// used as secondary indexes belong to the whole series
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Record {
     timestamp: u128,
     value: f64,
@@ -47,167 +59,961 @@ impl PartialEq for Record {
 
 impl Record {
     pub fn new(value: f64) -> Record {
-        let ctime = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Unable to get now");
         Record {
-            timestamp: ctime.as_millis(),
-            value: value,
+            timestamp: now(),
+            value,
         }
     }
+
+    pub fn with_timestamp(timestamp: u128, value: f64) -> Record {
+        Record { timestamp, value }
+    }
+
+    pub fn timestamp(&self) -> u128 {
+        return self.timestamp;
+    }
+
+    pub fn value(&self) -> f64 {
+        return self.value;
+    }
 }
 
-// Main timeseries struct, just a name that univocally identifies it, an optional retention policy
-// which essentially defines how long the timeseries will be (as a difference of age between the
-// latest point inserted and the oldest). A creation time as information meta and a vector of
-// records, the points of the timeseries.
-pub struct TimeSeries {
-    name: String,
-    retention: Option<i64>,
-    ctime: u128,
-    records: Vec<Record>,
+// How to handle a point with the same timestamp of one already stored: reject it, keep the stored
+// one, replace it or merge the two values
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum DuplicatePolicy {
+    Block,
+    First,
+    Last,
+    Sum,
+    Min,
+    Max,
+}
+
+// Retention policy of a timeseries, defines how long the timeseries will be, either as the maximum
+// age in milliseconds of a point relative to the newest one or as the number of latest points to
+// keep
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum Retention {
+    Age(u128),
+    Count(usize),
+}
+
+// Statistics of a whole series, maintained as points are added and expired so reading them never
+// requires a scan of the points. Dropping a point holding the minimum or the maximum makes them
+// stale, they're then recomputed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Stats {
+    pub count: usize,
+    pub sum: f64,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub latest: Option<Record>,
+    #[serde(skip)]
+    stale: bool,
+}
+
+impl Stats {
+    #[cfg(test)]
+    pub fn mean(&self) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        return Some(self.sum / self.count as f64);
+    }
+
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = Some(self.min.map_or(value, |m| m.min(value)));
+        self.max = Some(self.max.map_or(value, |m| m.max(value)));
+    }
+
+    // A stored value changed after merging a duplicate point
+    fn replace(&mut self, old: f64, new: f64) {
+        self.sum += new - old;
+        if self.min == Some(old) && new > old || self.max == Some(old) && new < old {
+            self.stale = true;
+        }
+        self.min = self.min.map(|m| m.min(new));
+        self.max = self.max.map(|m| m.max(new));
+    }
+
+    fn remove(&mut self, dropped: &Summary) {
+        self.count -= dropped.count;
+        self.sum -= dropped.sum;
+        if self.min.is_some_and(|m| dropped.min <= m) || self.max.is_some_and(|m| dropped.max >= m)
+        {
+            self.stale = true;
+        }
+    }
+
+    fn remove_records(&mut self, dropped: &[Record]) {
+        if let Some(summary) = Summary::of(dropped.iter().map(|r| r.value)) {
+            self.remove(&summary);
+        }
+    }
+}
+
+// Functions to aggregate a set of values into a single one. Range is the difference between the
+// maximum and the minimum, variance and standard deviation are the population ones. Percentiles
+// are given as a fraction between 0 and 1, the median is the 0.5 one.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum Aggregator {
+    Avg,
+    Sum,
+    Count,
+    Min,
+    Max,
+    First,
+    Last,
+    Range,
+    StdDev,
+    Variance,
+    Median,
+    Percentile(f64),
+}
+
+impl Aggregator {
+    // Percentiles outside of [0, 1] have no meaning, NaN included
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Aggregator::Percentile(q) if !(0.0..=1.0).contains(q) => {
+                return Err(format!("Invalid percentile {}, must be between 0 and 1", q))
+            }
+            _ => return Ok(()),
+        }
+    }
 }
 
-impl Index<usize> for TimeSeries {
-    type Output = Record;
+// Exact q percentile of a set of values, interpolating between the two closest ranks. NaN values
+// are ignored, None if there are no others or q is not between 0 and 1.
+pub fn percentile(values: Vec<f64>, q: f64) -> Option<f64> {
+    if !(0.0..=1.0).contains(&q) {
+        return None;
+    }
+    let mut values: Vec<f64> = values.into_iter().filter(|v| !v.is_nan()).collect();
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let rank = q * (values.len() - 1) as f64;
+    let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+    return Some(values[lo] + (values[hi] - values[lo]) * (rank - lo as f64));
+}
 
-    fn index(&self, i: usize) -> &Record {
-        return &self.records[i];
+// How to fill the buckets without points of a bucketed aggregation: not at all, leaving them out,
+// with an explicit null, the value of the previous bucket, a constant, or interpolating the
+// surrounding buckets either linearly or as a step holding the previous value. Interpolations only
+// fill gaps between two buckets with points, never before the first or after the last one.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum FillPolicy {
+    None,
+    Null,
+    Previous,
+    Value(f64),
+    Linear,
+    Step,
+}
+
+// Lay the aggregated buckets over every bucket between lo and hi, aligned to multiples of the
+// interval, filling the empty ones according to the policy. Without a policy to fill them only
+// the non empty buckets are returned.
+pub fn fill(
+    buckets: &[(u128, f64)],
+    lo: u128,
+    hi: u128,
+    interval: u128,
+    policy: FillPolicy,
+) -> Vec<(u128, Option<f64>)> {
+    if policy == FillPolicy::None {
+        return buckets.iter().map(|(s, v)| (*s, Some(*v))).collect();
+    }
+    let mut filled = Vec::new();
+    let mut next = 0;
+    let mut start = lo - lo % interval;
+    while start <= hi {
+        while next < buckets.len() && buckets[next].0 < start {
+            next += 1;
+        }
+        if next < buckets.len() && buckets[next].0 == start {
+            filled.push((start, Some(buckets[next].1)));
+        } else {
+            let prev = if next > 0 {
+                buckets.get(next - 1)
+            } else {
+                None
+            };
+            let value = match (policy, prev, buckets.get(next)) {
+                (FillPolicy::Value(v), _, _) => Some(v),
+                (FillPolicy::Previous, Some((_, v)), _) => Some(*v),
+                (FillPolicy::Step, Some((_, v)), Some(_)) => Some(*v),
+                (FillPolicy::Linear, Some((t0, v0)), Some((t1, v1))) => {
+                    let ratio = (start - t0) as f64 / (t1 - t0) as f64;
+                    Some(v0 + (v1 - v0) * ratio)
+                }
+                _ => None,
+            };
+            filled.push((start, value));
+        }
+        start = match start.checked_add(interval) {
+            Some(s) => s,
+            None => break,
+        };
     }
+    return filled;
+}
+
+// Number of points accumulated in the uncompressed head of a timeseries before being compressed
+// into a chunk
+const CHUNK_SIZE: usize = 128;
+
+// Up to this number of points percentiles are computed exactly, sorting them, beyond they are
+// estimated through the sketches of the chunks
+pub const EXACT_PERCENTILE_POINTS: usize = 10_000;
+
+// Number of consecutive chunks sharing a sketch, a sketch costs about as much as a couple of
+// chunks, so one per chunk would undo most of the compression
+const SKETCH_CHUNKS: usize = 16;
+
+// Sketch of the values of a run of consecutive chunks. The sketch of the oldest run goes stale
+// when some of its points are dropped, as they can't be removed from it, its chunks are then
+// decoded instead until the whole run expires.
+#[derive(Serialize, Deserialize)]
+struct SketchBlock {
+    chunks: usize,
+    sketch: Option<Sketch>,
+}
+
+// Main timeseries struct, just a name that univocally identifies it, labels to select it through
+// the keyspace index, an optional retention policy and an optional policy for points sharing the
+// same timestamp, without one they're all kept. A creation time as information meta and the points
+// of the timeseries, sorted by timestamp: the oldest ones compressed in chunks, the latest ones in
+// a small uncompressed head where new points are appended. A tree over the summaries of the chunks
+// answers the simple aggregations of a range decoding only the chunks at its edges, sketches of
+// runs of chunks estimate its percentiles, while statistics of the whole series are kept up to
+// date on every change.
+#[derive(Serialize, Deserialize)]
+pub struct TimeSeries {
+    name: String,
+    labels: Labels,
+    retention: Option<Retention>,
+    duplicate_policy: Option<DuplicatePolicy>,
+    ctime: u128,
+    chunks: Vec<Chunk>,
+    head: Vec<Record>,
+    tree: SummaryTree,
+    sketches: Vec<SketchBlock>,
+    stats: Stats,
+    // Wall clock time of the first sweep since the last point was added, the series ages from
+    // there on
+    #[serde(skip)]
+    idle_since: Option<u128>,
 }
 
 impl TimeSeries {
-    pub fn new(name: String, retention: Option<i64>) -> TimeSeries {
-        let ctime = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Unable to get now");
+    #[cfg(test)]
+    pub fn new(
+        name: String,
+        retention: Option<Retention>,
+        duplicate_policy: Option<DuplicatePolicy>,
+    ) -> TimeSeries {
+        return TimeSeries::with_labels(name, Labels::new(), retention, duplicate_policy);
+    }
+
+    pub fn with_labels(
+        name: String,
+        labels: Labels,
+        retention: Option<Retention>,
+        duplicate_policy: Option<DuplicatePolicy>,
+    ) -> TimeSeries {
         TimeSeries {
-            name: name,
-            retention: retention,
-            ctime: ctime.as_millis(),
-            records: Vec::new(),
-        }
-    }
-
-    pub fn add_point(&mut self, r: Record) {
-        self.records.push(r);
-        if let Some(r) = self.retention {
-            let last = self.records.last().unwrap();
-            let oldest_valid = self.search(last.timestamp).unwrap_err() - r as usize;
-            // Shrink vector by dropping first 0..oldest_valid indexes values
-            self.records.drain(0..oldest_valid);
-        }
-    }
-
-    pub fn avg(&self) -> f64 {
-        let a: f64 = self.records.iter().map(|x| x.value).sum::<f64>() / self.records.len() as f64;
-        return a;
-    }
-
-    pub fn avg_interval(&self, interval: u128) -> Option<Vec<f64>> {
-        match self.records.first() {
-            Some(first) => {
-                let first_ts = (first.timestamp / interval) * interval;
-                let last = self.records.last().unwrap();
-                let last_ts = ((last.timestamp / interval) * interval) + interval;
-                let mut current_ts = first_ts + interval;
-                let mut avgs: Vec<f64> = Vec::new();
-                while current_ts <= last_ts {
-                    let range: Vec<f64> = self
-                        .records
-                        .iter()
-                        .filter(|v| v.timestamp > current_ts - interval && v.timestamp < current_ts)
-                        .map(|x| x.value)
-                        .collect();
-                    if range.len() > 0 {
-                        avgs.push(range.iter().sum::<f64>() / range.len() as f64);
+            name,
+            labels,
+            retention,
+            duplicate_policy,
+            ctime: now(),
+            chunks: Vec::new(),
+            head: Vec::new(),
+            tree: SummaryTree::default(),
+            sketches: Vec::new(),
+            stats: Stats::default(),
+            idle_since: None,
+        }
+    }
+
+    // Add a point to the timeseries, return false if it was rejected as a duplicate
+    pub fn add_point(&mut self, r: Record) -> bool {
+        let value = r.value;
+        let inserted = match self.locate(r.timestamp) {
+            None => {
+                let inserted = insert(&mut self.head, r, self.duplicate_policy);
+                if self.head.len() >= CHUNK_SIZE {
+                    self.chunks.push(Chunk::encode(&self.head));
+                    self.sketch_pushed();
+                    self.head.clear();
+                    self.rebuild_tree();
+                }
+                inserted
+            }
+            Some(i) => {
+                // Late point falling into an already compressed chunk, which is rebuilt, split in
+                // two halves once it outgrows the size of a chunk so backfills never pile up in
+                // a single one
+                let mut records = self.chunks[i].decode();
+                let inserted = insert(&mut records, r, self.duplicate_policy);
+                match inserted {
+                    Inserted::Rejected => (),
+                    _ if records.len() > CHUNK_SIZE => self.split_chunk(i, &records),
+                    Inserted::Added => {
+                        self.chunks[i] = Chunk::encode(&records);
+                        self.tree.update(i, *self.chunks[i].summary());
+                        let (b, _) = self.sketch_of(i);
+                        if let Some(sketch) = self.sketches[b].sketch.as_mut() {
+                            sketch.add(value);
+                        }
+                    }
+                    // The replaced value can't be removed from the sketch, it's rebuilt
+                    Inserted::Merged(..) => {
+                        self.chunks[i] = Chunk::encode(&records);
+                        self.tree.update(i, *self.chunks[i].summary());
+                        let (b, _) = self.sketch_of(i);
+                        self.resketch(b);
                     }
-                    current_ts += interval;
                 }
-                return Some(avgs);
+                inserted
             }
-            None => return None,
         };
+        match inserted {
+            Inserted::Rejected => return false,
+            Inserted::Added => self.stats.add(value),
+            Inserted::Merged(old, new) => self.stats.replace(old, new),
+        }
+        self.stats.latest = self.last();
+        self.idle_since = None;
+        // Retention is always relative to the newest point, wherever the new one landed
+        let newest = self.newest().unwrap();
+        self.trim(newest);
+        return true;
     }
 
-    pub fn len(&self) -> usize {
-        return self.records.len();
+    // Drop the points falling out of the retention policy of a series not written for a while,
+    // meant to be called periodically with the wall clock time. Ages stay relative to the newest
+    // point, moved forward by the time passed since the first call after the last write: a series
+    // backfilled with old points keeps them, while an idle one expires as time goes by.
+    pub fn expire(&mut self, now: u128) {
+        let idle_since = *self.idle_since.get_or_insert(now);
+        let newest = match self.newest() {
+            Some(newest) => newest,
+            None => return,
+        };
+        self.trim(newest.saturating_add(now.saturating_sub(idle_since)));
     }
 
-    pub fn is_empty(&self) -> bool {
-        return self.records.len() == 0;
+    fn trim(&mut self, now: u128) {
+        match self.retention {
+            Some(Retention::Age(age)) => self.drop_older(now.saturating_sub(age)),
+            Some(Retention::Count(count)) => self.drop_first(self.len().saturating_sub(count)),
+            None => (),
+        }
+        // Only when an extreme has been dropped the next one has to be found, the summaries of
+        // the chunks make it cheap
+        if self.stats.stale {
+            let summary = self.summary(0, u128::MAX);
+            self.stats.min = summary.map(|s| s.min);
+            self.stats.max = summary.map(|s| s.max);
+            self.stats.stale = false;
+        }
+        if self.stats.count == 0 {
+            self.stats = Stats::default();
+        }
     }
 
-    pub fn max(&self) -> Option<f64> {
-        if self.is_empty() {
-            return None;
+    // Drop every point with a timestamp lower than cutoff, whole chunks are discarded without
+    // decoding them
+    fn drop_older(&mut self, cutoff: u128) {
+        let expired = self.chunks.partition_point(|c| c.last_ts() < cutoff);
+        if expired > 0 {
+            for c in self.chunks.drain(0..expired) {
+                self.stats.remove(c.summary());
+            }
+            self.drop_sketches(expired);
+            self.rebuild_tree();
+        }
+        match self.chunks.first() {
+            Some(c) if c.first_ts() < cutoff => {
+                let (dropped, kept): (Vec<Record>, Vec<Record>) =
+                    c.iter().partition(|r| r.timestamp < cutoff);
+                self.stats.remove_records(&dropped);
+                self.chunks[0] = Chunk::encode(&kept);
+                self.tree.update(0, *self.chunks[0].summary());
+                self.sketches[0].sketch = None;
+            }
+            _ => {
+                let expired = self.head.partition_point(|r| r.timestamp < cutoff);
+                let dropped: Vec<Record> = self.head.drain(0..expired).collect();
+                self.stats.remove_records(&dropped);
+            }
+        }
+    }
+
+    // Drop the n oldest points
+    fn drop_first(&mut self, mut n: usize) {
+        let mut expired = 0;
+        while expired < self.chunks.len() && self.chunks[expired].len() <= n {
+            n -= self.chunks[expired].len();
+            expired += 1;
+        }
+        if expired > 0 {
+            for c in self.chunks.drain(0..expired) {
+                self.stats.remove(c.summary());
+            }
+            self.drop_sketches(expired);
+            self.rebuild_tree();
+        }
+        if n == 0 {
+            return;
+        }
+        match self.chunks.first() {
+            Some(c) => {
+                let records = c.decode();
+                self.stats.remove_records(&records[..n]);
+                self.chunks[0] = Chunk::encode(&records[n..]);
+                self.tree.update(0, *self.chunks[0].summary());
+                self.sketches[0].sketch = None;
+            }
+            None => {
+                let dropped: Vec<Record> = self.head.drain(0..n).collect();
+                self.stats.remove_records(&dropped);
+            }
         }
-        let first = self.records[0].value;
-        return Some(
-            self.records
-                .iter()
-                .map(|x| x.value)
-                .fold(first, |max, val| if val > max { val } else { max }),
-        );
     }
 
-    pub fn min(&self) -> Option<f64> {
-        if self.is_empty() {
+    // Where a point with the given timestamp belongs, None for the head, which takes anything not
+    // older than its first point or newer than every chunk, otherwise the index of the chunk
+    // spanning the timestamp or preceding the gap between chunks it falls in
+    fn locate(&self, timestamp: u128) -> Option<usize> {
+        if let Some(first) = self.head.first() {
+            if timestamp >= first.timestamp {
+                return None;
+            }
+        }
+        let i = self.chunks.partition_point(|c| c.first_ts() <= timestamp);
+        if i == 0 {
+            if self.chunks.is_empty() {
+                return None;
+            }
+            return Some(0);
+        }
+        if i == self.chunks.len() && timestamp > self.chunks[i - 1].last_ts() {
             return None;
         }
-        let first = self.records[0].value;
-        return Some(
-            self.records
+        return Some(i - 1);
+    }
+
+    fn rebuild_tree(&mut self) {
+        self.tree = SummaryTree::new(self.chunks.iter().map(|c| *c.summary()).collect());
+    }
+
+    // Add the values of the chunk just pushed to the sketch of the newest run, or start a new one
+    fn sketch_pushed(&mut self) {
+        let chunk = self.chunks.last().unwrap();
+        match self.sketches.last_mut() {
+            Some(SketchBlock {
+                chunks,
+                sketch: Some(sketch),
+            }) if *chunks < SKETCH_CHUNKS => {
+                chunk.iter().for_each(|r| sketch.add(r.value));
+                *chunks += 1;
+            }
+            _ => {
+                let mut sketch = Sketch::new();
+                chunk.iter().for_each(|r| sketch.add(r.value));
+                self.sketches.push(SketchBlock {
+                    chunks: 1,
+                    sketch: Some(sketch),
+                });
+            }
+        }
+    }
+
+    // The run of chunks holding the i-th chunk, along with the index of its first chunk
+    fn sketch_of(&self, mut i: usize) -> (usize, usize) {
+        let mut start = 0;
+        for (b, block) in self.sketches.iter().enumerate() {
+            if i < block.chunks {
+                return (b, start);
+            }
+            i -= block.chunks;
+            start += block.chunks;
+        }
+        unreachable!("every chunk belongs to a run");
+    }
+
+    // Rebuild the sketch of the b-th run of chunks, unless it's stale
+    fn resketch(&mut self, b: usize) {
+        let start: usize = self.sketches[..b].iter().map(|block| block.chunks).sum();
+        let block = &mut self.sketches[b];
+        if let Some(sketch) = block.sketch.as_mut() {
+            *sketch = Sketch::new();
+            self.chunks[start..start + block.chunks]
                 .iter()
-                .map(|x| x.value)
-                .fold(first, |min, val| if min < val { min } else { val }),
-        );
+                .flat_map(|c| c.iter())
+                .for_each(|r| sketch.add(r.value));
+        }
     }
 
-    pub fn search(&self, val: u128) -> Result<usize, usize> {
+    // Replace the i-th chunk with two halves of the records given, splitting its run of chunks as
+    // well if it gets too long
+    fn split_chunk(&mut self, i: usize, records: &[Record]) {
+        let (left, right) = records.split_at(records.len() / 2);
+        self.chunks[i] = Chunk::encode(left);
+        self.chunks.insert(i + 1, Chunk::encode(right));
+        self.rebuild_tree();
+        let (b, _) = self.sketch_of(i);
+        self.sketches[b].chunks += 1;
+        let chunks = self.sketches[b].chunks;
+        if chunks > SKETCH_CHUNKS {
+            self.sketches[b].chunks = chunks / 2;
+            let sketch = self.sketches[b].sketch.as_ref().map(|_| Sketch::new());
+            self.sketches.insert(
+                b + 1,
+                SketchBlock {
+                    chunks: chunks - chunks / 2,
+                    sketch,
+                },
+            );
+            self.resketch(b + 1);
+        }
+        self.resketch(b);
+    }
+
+    // Forget the n oldest chunks, just drained, the run left with some of them goes stale
+    fn drop_sketches(&mut self, mut n: usize) {
+        let whole = self
+            .sketches
+            .iter()
+            .take_while(|b| {
+                let dropped = b.chunks <= n;
+                if dropped {
+                    n -= b.chunks;
+                }
+                dropped
+            })
+            .count();
+        self.sketches.drain(0..whole);
+        if n > 0 {
+            self.sketches[0].chunks -= n;
+            self.sketches[0].sketch = None;
+        }
+    }
+
+    // The point with the highest timestamp, without decoding any chunk
+    fn last(&self) -> Option<Record> {
+        match self.head.last() {
+            Some(r) => return Some(r.clone()),
+            None => {
+                let c = self.chunks.last()?;
+                return Some(Record::with_timestamp(c.last_ts(), c.summary().last));
+            }
+        }
+    }
+
+    fn newest(&self) -> Option<u128> {
+        match self.head.last() {
+            Some(r) => return Some(r.timestamp),
+            None => return self.chunks.last().map(|c| c.last_ts()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        return &self.name;
+    }
+
+    pub fn labels(&self) -> &Labels {
+        return &self.labels;
+    }
+
+    pub fn retention(&self) -> Option<Retention> {
+        return self.retention;
+    }
+
+    pub fn ctime(&self) -> u128 {
+        return self.ctime;
+    }
+
+    pub fn stats(&self) -> &Stats {
+        return &self.stats;
+    }
+
+    pub fn duplicate_policy(&self) -> Option<DuplicatePolicy> {
+        return self.duplicate_policy;
+    }
+
+    // Tell if a point with the given timestamp would be rejected as a duplicate
+    pub fn rejects(&self, timestamp: u128) -> bool {
+        if self.duplicate_policy != Some(DuplicatePolicy::Block) {
+            return false;
+        }
+        match self.locate(timestamp) {
+            Some(i) => return self.chunks[i].iter().any(|r| r.timestamp == timestamp),
+            None => {
+                let i = self.head.partition_point(|r| r.timestamp < timestamp);
+                return i < self.head.len() && self.head[i].timestamp == timestamp;
+            }
+        }
+    }
+
+    // Iterate over every point of the timeseries in time order, decoding chunks on the fly
+    pub fn iter(&self) -> impl Iterator<Item = Record> + '_ {
         return self
-            .records
-            .binary_search_by(|r| r.timestamp.cmp(&val).then(Ordering::Greater));
+            .chunks
+            .iter()
+            .flat_map(|c| c.iter())
+            .chain(self.head.iter().cloned());
+    }
+
+    // Aggregate the points within the [lo, hi] range, None if there's none. Simple aggregations
+    // come from the summaries of the chunks, percentiles from sketches, only the others need
+    // to scan the whole range.
+    pub fn aggregate(&self, lo: u128, hi: u128, aggregator: Aggregator) -> Option<f64> {
+        match aggregator {
+            Aggregator::Median => return self.percentile(lo, hi, 0.5),
+            Aggregator::Percentile(q) => return self.percentile(lo, hi, q),
+            Aggregator::StdDev | Aggregator::Variance => {
+                let mut records = self.range_iter(lo..=hi);
+                let mut bucket = aggregate::Bucket::new(records.next()?, aggregator);
+                records.for_each(|r| bucket.add(r));
+                return Some(bucket.value(aggregator));
+            }
+            _ => return self.summary(lo, hi)?.aggregate(aggregator),
+        }
     }
 
-    pub fn range(&self, lo: u128, hi: u128) -> Option<Vec<Record>> {
-        if self.is_empty() {
+    // Summary of the points within the [lo, hi] range, None if there's none. Only the chunks
+    // partially within the range are decoded.
+    pub fn summary(&self, lo: u128, hi: u128) -> Option<Summary> {
+        let partial = |c: &Chunk| {
+            let values = c
+                .iter()
+                .filter(|r| r.timestamp >= lo && r.timestamp <= hi)
+                .map(|r| r.value);
+            return Summary::of(values);
+        };
+        // Chunks in [first, last) overlap the range, all but the ones at the edges are within it
+        let mut first = self.chunks.partition_point(|c| c.last_ts() < lo);
+        let mut last = self.chunks.partition_point(|c| c.first_ts() <= hi);
+        let mut result = None;
+        if first < last && self.chunks[first].first_ts() < lo {
+            result = partial(&self.chunks[first]);
+            first += 1;
+        }
+        let mut tail = None;
+        if first < last && self.chunks[last - 1].last_ts() > hi {
+            tail = partial(&self.chunks[last - 1]);
+            last -= 1;
+        }
+        if first < last {
+            result = summary::combine(result, self.tree.query(first, last));
+        }
+        result = summary::combine(result, tail);
+        let head = self
+            .head
+            .iter()
+            .filter(|r| r.timestamp >= lo && r.timestamp <= hi)
+            .map(|r| r.value);
+        return summary::combine(result, Summary::of(head));
+    }
+
+    // The q percentile of the values within the [lo, hi] range, with q between 0 and 1, None if
+    // there are none or q is not valid. Large ranges are estimated merging the sketches of the
+    // runs of chunks entirely within the range, without decoding them, so the result is within
+    // the relative accuracy of the sketches.
+    pub fn percentile(&self, lo: u128, hi: u128, q: f64) -> Option<f64> {
+        if !(0.0..=1.0).contains(&q) {
             return None;
         }
-        let start = self.search(lo).unwrap_err();
-        let end = self.search(hi).unwrap_err();
-        return Some(self.records[start..end + 1].to_vec());
+        let inside = |c: &Chunk| c.first_ts() >= lo && c.last_ts() <= hi;
+        let covered: usize = self
+            .chunks
+            .iter()
+            .filter(|c| inside(c))
+            .map(|c| c.len())
+            .sum();
+        if covered <= EXACT_PERCENTILE_POINTS {
+            let values: Vec<f64> = self.range_iter(lo..=hi).map(|r| r.value).collect();
+            return percentile(values, q);
+        }
+        let mut sketch = Sketch::new();
+        let mut start = 0;
+        for block in &self.sketches {
+            let chunks = &self.chunks[start..start + block.chunks];
+            start += block.chunks;
+            match &block.sketch {
+                Some(s) if chunks.iter().all(inside) => sketch.merge(s),
+                _ => chunks
+                    .iter()
+                    .filter(|c| c.first_ts() <= hi && c.last_ts() >= lo)
+                    .flat_map(|c| c.iter())
+                    .filter(|r| r.timestamp >= lo && r.timestamp <= hi)
+                    .for_each(|r| sketch.add(r.value)),
+            }
+        }
+        self.head
+            .iter()
+            .filter(|r| r.timestamp >= lo && r.timestamp <= hi)
+            .for_each(|r| sketch.add(r.value));
+        return sketch.quantile(q);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.chunks.iter().map(|c| c.len()).sum::<usize>() + self.head.len();
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        return self.chunks.is_empty() && self.head.is_empty();
+    }
+
+    // Points within the range, oldest first. Records are decoded lazily, chunk by chunk, and the
+    // chunks entirely outside the range are never decoded.
+    pub fn range_iter<R: RangeBounds<u128>>(&self, range: R) -> impl Iterator<Item = Record> + '_ {
+        let (lo, hi) = closed(range);
+        let first = self.chunks.partition_point(|c| c.last_ts() < lo);
+        return self.chunks[first..]
+            .iter()
+            .take_while(move |c| c.first_ts() <= hi)
+            .flat_map(|c| c.iter())
+            .chain(self.head.iter().cloned())
+            .skip_while(move |r| r.timestamp < lo)
+            .take_while(move |r| r.timestamp <= hi);
+    }
+
+    // Points within the range, newest first. Chunks can only be decoded forward, so each one is
+    // decoded whole right before its first record is needed.
+    pub fn range_rev<R: RangeBounds<u128>>(&self, range: R) -> impl Iterator<Item = Record> + '_ {
+        let (lo, hi) = closed(range);
+        let last = self.chunks.partition_point(|c| c.first_ts() <= hi);
+        let chunks = self.chunks[..last]
+            .iter()
+            .rev()
+            .take_while(move |c| c.last_ts() >= lo)
+            .flat_map(|c| c.decode().into_iter().rev());
+        return self
+            .head
+            .iter()
+            .rev()
+            .cloned()
+            .chain(chunks)
+            .skip_while(move |r| r.timestamp > hi)
+            .take_while(move |r| r.timestamp >= lo);
+    }
+
+    // Points within the [lo, hi] range, empty if there are none
+    pub fn range(&self, lo: u128, hi: u128) -> Vec<Record> {
+        return self.range_iter(lo..=hi).collect();
+    }
+}
+
+// Inclusive timestamp bounds of a range, with lo greater than hi if it can't hold any point
+fn closed<R: RangeBounds<u128>>(range: R) -> (u128, u128) {
+    let lo = match range.start_bound() {
+        Bound::Included(&lo) => lo,
+        Bound::Excluded(&lo) => match lo.checked_add(1) {
+            Some(lo) => lo,
+            None => return (1, 0),
+        },
+        Bound::Unbounded => 0,
+    };
+    let hi = match range.end_bound() {
+        Bound::Included(&hi) => hi,
+        Bound::Excluded(&hi) => match hi.checked_sub(1) {
+            Some(hi) => hi,
+            None => return (1, 0),
+        },
+        Bound::Unbounded => u128::MAX,
+    };
+    return (lo, hi);
+}
+
+// Outcome of inserting a record, merged records carry their value before and after the merge
+enum Inserted {
+    Rejected,
+    Added,
+    Merged(f64, f64),
+}
+
+// Insert a record into a sorted vector after every record with a lower or equal timestamp, or
+// merge it with the one with the same timestamp according to the duplicate policy
+fn insert(records: &mut Vec<Record>, r: Record, policy: Option<DuplicatePolicy>) -> Inserted {
+    // Points mostly arrive in time order and are just appended
+    let i = match records.last() {
+        Some(last) if r.timestamp <= last.timestamp => {
+            records.partition_point(|x| x.timestamp <= r.timestamp)
+        }
+        _ => records.len(),
+    };
+    match policy {
+        Some(policy) if i > 0 && records[i - 1].timestamp == r.timestamp => {
+            let stored = &mut records[i - 1];
+            let old = stored.value;
+            match policy {
+                DuplicatePolicy::Block => return Inserted::Rejected,
+                DuplicatePolicy::First => (),
+                DuplicatePolicy::Last => stored.value = r.value,
+                DuplicatePolicy::Sum => stored.value += r.value,
+                DuplicatePolicy::Min => stored.value = stored.value.min(r.value),
+                DuplicatePolicy::Max => stored.value = stored.value.max(r.value),
+            }
+            return Inserted::Merged(old, stored.value);
+        }
+        _ => records.insert(i, r),
     }
+    return Inserted::Added;
 }
 
 //////////////////////
 ///   UNIT TESTS   ///
 //////////////////////
-
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    fn get(ts: &TimeSeries, i: usize) -> Option<Record> {
+        return ts.iter().nth(i);
+    }
+
+    fn avg(ts: &TimeSeries) -> Option<f64> {
+        return ts.aggregate(0, u128::MAX, Aggregator::Avg);
+    }
+
+    fn min(ts: &TimeSeries) -> Option<f64> {
+        return ts.aggregate(0, u128::MAX, Aggregator::Min);
+    }
+
+    fn max(ts: &TimeSeries) -> Option<f64> {
+        return ts.aggregate(0, u128::MAX, Aggregator::Max);
+    }
+
+    fn buckets(ts: &TimeSeries, interval: u128, aggregator: Aggregator) -> Vec<(u128, f64)> {
+        return aggregate::bucketize(ts.iter(), interval, aggregator);
+    }
+
+    // Position of the first point with a timestamp not lower than val
+    fn search(ts: &TimeSeries, val: u128) -> usize {
+        return ts.range_iter(..val).count();
+    }
 
     #[test]
     fn test_ts_new() {
-        let ts = TimeSeries::new("test-ts".to_string(), Some(3000));
+        let ts = TimeSeries::new("test-ts".to_string(), Some(Retention::Age(3000)), None);
         assert_eq!(ts.name, "test-ts");
-        assert_eq!(ts.retention, Some(3000));
+        assert_eq!(ts.retention, Some(Retention::Age(3000)));
     }
 
     #[test]
     fn test_ts_add_point() {
-        let mut ts = TimeSeries::new("test-ts".to_string(), None);
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, None);
         let r = Record::new(12.98);
         ts.add_point(r);
-        assert_eq!(ts.records.len(), 1);
-        assert_eq!(ts.records[0].value, 12.98);
+        assert_eq!(ts.len(), 1);
+        assert_eq!(get(&ts, 0).unwrap().value, 12.98);
+    }
+
+    #[test]
+    fn test_ts_add_point_out_of_order() {
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, None);
+        ts.add_point(Record::with_timestamp(30, 11.28));
+        ts.add_point(Record::with_timestamp(10, 12.98));
+        ts.add_point(Record::with_timestamp(40, 15.96));
+        ts.add_point(Record::with_timestamp(20, 19.63));
+        ts.add_point(Record::with_timestamp(20, 21.04));
+        let timestamps: Vec<u128> = ts.iter().map(|r| r.timestamp).collect();
+        assert_eq!(timestamps, [10, 20, 20, 30, 40]);
+        // Points sharing a timestamp keep their arrival order
+        assert_eq!(get(&ts, 1).unwrap().value, 19.63);
+        assert_eq!(get(&ts, 2).unwrap().value, 21.04);
+    }
+
+    #[test]
+    fn test_ts_add_point_out_of_order_retention() {
+        let mut ts = TimeSeries::new("test-ts".to_string(), Some(Retention::Age(15)), None);
+        ts.add_point(Record::with_timestamp(10, 12.98));
+        ts.add_point(Record::with_timestamp(20, 19.63));
+        ts.add_point(Record::with_timestamp(30, 11.28));
+        ts.add_point(Record::with_timestamp(40, 15.96));
+        ts.add_point(Record::with_timestamp(27, 21.04));
+        ts.add_point(Record::with_timestamp(22, 1.0));
+        let timestamps: Vec<u128> = ts.iter().map(|r| r.timestamp).collect();
+        assert_eq!(timestamps, [27, 30, 40]);
+    }
+
+    #[test]
+    fn test_ts_retention_count() {
+        let mut ts = TimeSeries::new("test-ts".to_string(), Some(Retention::Count(3)), None);
+        ts.add_point(Record::with_timestamp(10, 12.98));
+        ts.add_point(Record::with_timestamp(20, 19.63));
+        assert_eq!(ts.len(), 2);
+        ts.add_point(Record::with_timestamp(30, 11.28));
+        ts.add_point(Record::with_timestamp(40, 15.96));
+        ts.add_point(Record::with_timestamp(15, 21.04));
+        let timestamps: Vec<u128> = ts.iter().map(|r| r.timestamp).collect();
+        assert_eq!(timestamps, [20, 30, 40]);
+    }
+
+    #[test]
+    fn test_ts_expire() {
+        let mut ts = TimeSeries::new("test-ts".to_string(), Some(Retention::Age(100)), None);
+        ts.add_point(Record::with_timestamp(10, 12.98));
+        ts.add_point(Record::with_timestamp(60, 19.63));
+        ts.add_point(Record::with_timestamp(90, 11.28));
+        // Backfilled points are kept however old, the series ages from the first sweep on
+        ts.expire(1_000_000);
+        assert_eq!(ts.len(), 3);
+        ts.expire(1_000_060);
+        assert_eq!(ts.len(), 2);
+        // A new point restarts the clock
+        ts.add_point(Record::with_timestamp(100, 1.0));
+        ts.expire(1_000_500);
+        assert_eq!(ts.len(), 3);
+        ts.expire(1_001_000);
+        assert!(ts.is_empty());
+        // Points close to the end of time never overflow the age of the series
+        let mut ts = TimeSeries::new("test-ts".to_string(), Some(Retention::Age(100)), None);
+        ts.add_point(Record::with_timestamp(u128::MAX - 10, 1.0));
+        ts.expire(1000);
+        ts.expire(2000);
+        assert_eq!(ts.len(), 1);
+    }
+
+    #[test]
+    fn test_ts_add_point_duplicate_policy() {
+        let policies = [
+            (DuplicatePolicy::Block, 12.98),
+            (DuplicatePolicy::First, 12.98),
+            (DuplicatePolicy::Last, 19.63),
+            (DuplicatePolicy::Sum, 32.61),
+            (DuplicatePolicy::Min, 12.98),
+            (DuplicatePolicy::Max, 19.63),
+        ];
+        for (policy, value) in policies.iter() {
+            let mut ts = TimeSeries::new("test-ts".to_string(), None, Some(*policy));
+            assert!(ts.add_point(Record::with_timestamp(10, 12.98)));
+            assert!(ts.add_point(Record::with_timestamp(20, 1.0)));
+            let added = ts.add_point(Record::with_timestamp(10, 19.63));
+            assert_eq!(added, *policy != DuplicatePolicy::Block);
+            assert_eq!(ts.rejects(10), *policy == DuplicatePolicy::Block);
+            assert_eq!(ts.len(), 2);
+            assert_eq!(get(&ts, 0).unwrap().value, *value);
+        }
     }
 
     #[test]
     fn test_ts_avg() {
-        let mut ts = TimeSeries::new("test-ts".to_string(), None);
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, None);
         let r1 = Record::new(12.98);
         let r2 = Record::new(19.63);
         let r3 = Record::new(11.28);
@@ -216,13 +1022,12 @@ mod tests {
         ts.add_point(r2);
         ts.add_point(r3);
         ts.add_point(r4);
-        let avg = ts.avg();
-        assert_eq!(avg, 14.9625);
+        assert_eq!(avg(&ts), Some(14.9625));
     }
 
     #[test]
-    fn test_ts_avg_interval() {
-        let mut ts = TimeSeries::new("test-ts".to_string(), None);
+    fn test_ts_buckets_avg() {
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, None);
         let r1 = Record::new(12.98);
         sleep(Duration::new(0, 5e8 as u32));
         let r2 = Record::new(19.63);
@@ -233,13 +1038,207 @@ mod tests {
         ts.add_point(r2);
         ts.add_point(r3);
         ts.add_point(r4);
-        let avg = ts.avg_interval(500 as u128).unwrap();
+        let buckets = buckets(&ts, 500_u128, Aggregator::Avg);
+        let avg: Vec<f64> = buckets.iter().map(|(_, v)| *v).collect();
         assert_eq!(avg, [12.98, 15.454999999999998, 15.96]);
+        assert!(buckets.iter().all(|(start, _)| start % 500 == 0));
+    }
+
+    #[test]
+    fn test_ts_buckets() {
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, None);
+        for (timestamp, value) in &[
+            (0, 4.0),
+            (3, 1.0),
+            (7, 3.0),
+            (12, 2.0),
+            (31, 8.0),
+            (39, 6.0),
+        ] {
+            ts.add_point(Record::with_timestamp(*timestamp, *value));
+        }
+        let of = |a| buckets(&ts, 10, a);
+        assert_eq!(of(Aggregator::Sum), [(0, 8.0), (10, 2.0), (30, 14.0)]);
+        assert_eq!(of(Aggregator::Count), [(0, 3.0), (10, 1.0), (30, 2.0)]);
+        assert_eq!(of(Aggregator::Min), [(0, 1.0), (10, 2.0), (30, 6.0)]);
+        assert_eq!(of(Aggregator::Max), [(0, 4.0), (10, 2.0), (30, 8.0)]);
+        assert_eq!(of(Aggregator::First), [(0, 4.0), (10, 2.0), (30, 8.0)]);
+        assert_eq!(of(Aggregator::Last), [(0, 3.0), (10, 2.0), (30, 6.0)]);
+        assert_eq!(of(Aggregator::Range), [(0, 3.0), (10, 0.0), (30, 2.0)]);
+        assert_eq!(of(Aggregator::Median), [(0, 3.0), (10, 2.0), (30, 7.0)]);
+        assert_eq!(of(Aggregator::Variance)[2], (30, 1.0));
+        assert_eq!(of(Aggregator::StdDev)[2], (30, 1.0));
+        assert_eq!(ts.aggregate(0, u128::MAX, Aggregator::Sum), Some(24.0));
+        assert_eq!(ts.aggregate(30, 40, Aggregator::Variance), Some(1.0));
+        let empty = TimeSeries::new("test-ts".to_string(), None, None);
+        assert!(buckets(&empty, 10, Aggregator::Avg).is_empty());
+        assert_eq!(empty.aggregate(0, u128::MAX, Aggregator::Avg), None);
+    }
+
+    #[test]
+    fn test_ts_percentile() {
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, None);
+        for i in 0..100_000 {
+            // Scramble the values so they're not sorted by timestamp
+            ts.add_point(Record::with_timestamp(i, ((i * 7919) % 100_000) as f64));
+        }
+        // Small ranges are exact
+        let exact = percentile(
+            (0..=100).map(|i| ((i * 7919) % 100_000) as f64).collect(),
+            0.5,
+        );
+        assert_eq!(ts.percentile(0, 100, 0.5), exact);
+        // Large ones within the sketch accuracy
+        for q in &[0.5, 0.95, 0.99] {
+            let estimate = ts.percentile(0, 99_999, *q).unwrap();
+            let exact = q * 99_999.0;
+            assert!((estimate - exact).abs() <= exact * 0.01);
+        }
+        assert_eq!(ts.percentile(200_000, 300_000, 0.5), None);
+        let values = vec![4.0, 1.0, 3.0, 2.0];
+        assert_eq!(percentile(values.clone(), 0.0), Some(1.0));
+        assert_eq!(percentile(values.clone(), 0.5), Some(2.5));
+        assert_eq!(percentile(values.clone(), 0.9), Some(3.7));
+        // Aggregate values sharing the same bucket
+        let apply = |aggregator, values: &[f64]| {
+            let records = values.iter().map(|v| Record::with_timestamp(0, *v));
+            return aggregate::bucketize(records, 1, aggregator)[0].1;
+        };
+        assert_eq!(apply(Aggregator::Percentile(1.0), &values), 4.0);
+        // Invalid percentiles never panic, NaN values are ignored
+        for q in &[-0.1, 1.5, f64::NAN] {
+            assert!(Aggregator::Percentile(*q).validate().is_err());
+            assert!(apply(Aggregator::Percentile(*q), &values).is_nan());
+            assert_eq!(ts.percentile(0, 99_999, *q), None);
+        }
+        assert!(Aggregator::Percentile(0.5).validate().is_ok());
+        assert_eq!(apply(Aggregator::Median, &[f64::NAN, 1.0, 3.0]), 2.0);
+        assert!(apply(Aggregator::Median, &[f64::NAN]).is_nan());
+    }
+
+    #[test]
+    fn test_ts_backfill() {
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, None);
+        for i in 0..200 {
+            ts.add_point(Record::with_timestamp(100_000 + i, 1.0));
+        }
+        // Older points inserted newest first all fall before the first chunk
+        for i in (0..20_000).rev() {
+            ts.add_point(Record::with_timestamp(i * 5, (i % 1000) as f64));
+        }
+        assert_eq!(ts.len(), 20_200);
+        assert!(ts.chunks.iter().all(|c| c.len() <= CHUNK_SIZE));
+        assert!(ts.sketches.iter().all(|b| b.chunks <= SKETCH_CHUNKS));
+        assert_eq!(
+            ts.sketches.iter().map(|b| b.chunks).sum::<usize>(),
+            ts.chunks.len()
+        );
+        let timestamps: Vec<u128> = ts.iter().map(|r| r.timestamp).collect();
+        assert!(timestamps.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(ts.summary(0, u128::MAX).unwrap().count, 20_200);
+        let estimate = ts.percentile(0, 99_995, 0.5).unwrap();
+        assert!((estimate - 499.5).abs() <= 499.5 * 0.01);
+    }
+
+    #[test]
+    fn test_ts_percentile_sketches() {
+        let value = |i: u128| ((i * 7919) % 100_000) as f64;
+        let mut ts = TimeSeries::new("test-ts".to_string(), Some(Retention::Count(90_000)), None);
+        for i in 0..100_000 {
+            ts.add_point(Record::with_timestamp(i, value(i)));
+        }
+        // A sketch every run of chunks rather than every chunk
+        assert_eq!(ts.chunks.len(), 703);
+        assert_eq!(ts.sketches.len(), 45);
+        assert_eq!(ts.sketches.iter().map(|b| b.chunks).sum::<usize>(), 703);
+        // The oldest run lost some of its points, it's decoded instead
+        assert!(ts.sketches[0].sketch.is_none());
+        assert!(ts.sketches[1..].iter().all(|b| b.sketch.is_some()));
+        // Late points rebuild the sketch of their run
+        ts.add_point(Record::with_timestamp(50_000, 1e9));
+        for q in &[0.01, 0.5, 0.99, 1.0] {
+            let mut values: Vec<f64> = (10_000..100_000).map(value).collect();
+            values.push(1e9);
+            let exact = percentile(values, *q).unwrap();
+            let estimate = ts.percentile(0, 100_000, *q).unwrap();
+            assert!((estimate - exact).abs() <= exact * 0.01);
+        }
+    }
+
+    #[test]
+    fn test_fill() {
+        let buckets = [(10, 1.0), (40, 4.0), (50, 2.0)];
+        let values = |policy| -> Vec<Option<f64>> {
+            return fill(&buckets, 0, 65, 10, policy)
+                .into_iter()
+                .map(|(_, v)| v)
+                .collect();
+        };
+        assert_eq!(values(FillPolicy::None), [Some(1.0), Some(4.0), Some(2.0)]);
+        assert_eq!(
+            values(FillPolicy::Null),
+            [None, Some(1.0), None, None, Some(4.0), Some(2.0), None]
+        );
+        assert_eq!(
+            values(FillPolicy::Previous),
+            [
+                None,
+                Some(1.0),
+                Some(1.0),
+                Some(1.0),
+                Some(4.0),
+                Some(2.0),
+                Some(2.0)
+            ]
+        );
+        assert_eq!(
+            values(FillPolicy::Value(0.0)),
+            [
+                Some(0.0),
+                Some(1.0),
+                Some(0.0),
+                Some(0.0),
+                Some(4.0),
+                Some(2.0),
+                Some(0.0)
+            ]
+        );
+        assert_eq!(
+            values(FillPolicy::Linear),
+            [
+                None,
+                Some(1.0),
+                Some(2.0),
+                Some(3.0),
+                Some(4.0),
+                Some(2.0),
+                None
+            ]
+        );
+        assert_eq!(
+            values(FillPolicy::Step),
+            [
+                None,
+                Some(1.0),
+                Some(1.0),
+                Some(1.0),
+                Some(4.0),
+                Some(2.0),
+                None
+            ]
+        );
+        // Bounds are aligned to the interval
+        let starts: Vec<u128> = fill(&buckets, 5, 65, 10, FillPolicy::Null)
+            .into_iter()
+            .map(|(s, _)| s)
+            .collect();
+        assert_eq!(starts, [0, 10, 20, 30, 40, 50, 60]);
+        assert_eq!(fill(&[], 0, 0, 10, FillPolicy::Null), [(0, None)]);
     }
 
     #[test]
     fn test_ts_index() {
-        let mut ts = TimeSeries::new("test-ts".to_string(), None);
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, None);
         let r1 = Record::new(12.98);
         let r2 = Record::new(19.63);
         let r3 = Record::new(11.28);
@@ -248,13 +1247,13 @@ mod tests {
         ts.add_point(r2);
         ts.add_point(r3);
         ts.add_point(r4);
-        assert_eq!(ts[1].value, 19.63);
-        assert_eq!(ts[3].value, 15.96);
+        assert_eq!(get(&ts, 1).unwrap().value, 19.63);
+        assert_eq!(get(&ts, 3).unwrap().value, 15.96);
     }
 
     #[test]
     fn test_ts_len() {
-        let mut ts = TimeSeries::new("test-ts".to_string(), None);
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, None);
         assert_eq!(ts.len(), 0);
         let r1 = Record::new(12.98);
         ts.add_point(r1);
@@ -263,16 +1262,16 @@ mod tests {
 
     #[test]
     fn test_ts_is_empty() {
-        let mut ts = TimeSeries::new("test-ts".to_string(), None);
-        assert_eq!(ts.is_empty(), true);
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, None);
+        assert!(ts.is_empty());
         let r1 = Record::new(12.98);
         ts.add_point(r1);
-        assert_eq!(ts.is_empty(), false);
+        assert!(!ts.is_empty());
     }
 
     #[test]
     fn test_ts_max() {
-        let mut ts = TimeSeries::new("test-ts".to_string(), None);
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, None);
         let r1 = Record::new(12.98);
         let r2 = Record::new(19.63);
         let r3 = Record::new(11.28);
@@ -281,12 +1280,12 @@ mod tests {
         ts.add_point(r2);
         ts.add_point(r3);
         ts.add_point(r4);
-        assert_eq!(ts.max(), Some(19.63));
+        assert_eq!(max(&ts), Some(19.63));
     }
 
     #[test]
     fn test_ts_min() {
-        let mut ts = TimeSeries::new("test-ts".to_string(), None);
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, None);
         let r1 = Record::new(12.98);
         let r2 = Record::new(19.63);
         let r3 = Record::new(11.28);
@@ -295,12 +1294,12 @@ mod tests {
         ts.add_point(r2);
         ts.add_point(r3);
         ts.add_point(r4);
-        assert_eq!(ts.min(), Some(11.28));
+        assert_eq!(min(&ts), Some(11.28));
     }
 
     #[test]
     fn test_ts_search() {
-        let mut ts = TimeSeries::new("test-ts".to_string(), None);
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, None);
         let r1 = Record::new(12.98);
         sleep(Duration::new(0, 5e8 as u32));
         let r2 = Record::new(19.63);
@@ -313,13 +1312,13 @@ mod tests {
         ts.add_point(r2);
         ts.add_point(r3);
         ts.add_point(r4);
-        assert_eq!(ts.search(timestamp_1), Err(1));
-        assert_eq!(ts.search(timestamp_2), Err(3));
+        assert_eq!(search(&ts, timestamp_1), 1);
+        assert_eq!(search(&ts, timestamp_2), 3);
     }
 
     #[test]
     fn test_ts_range() {
-        let mut ts = TimeSeries::new("test-ts".to_string(), None);
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, None);
         let r1 = Record::new(12.98);
         sleep(Duration::new(0, 5e8 as u32));
         let r2 = Record::new(19.63);
@@ -332,15 +1331,219 @@ mod tests {
         ts.add_point(r2);
         ts.add_point(r3);
         ts.add_point(r4);
-        let range = ts.range(timestamp_1, timestamp_2).unwrap();
+        let range = ts.range(timestamp_1, timestamp_2);
         assert_eq!(range.len(), 3);
         assert_eq!(range[0].value, 19.63);
         assert_eq!(range[2].value, 15.96);
     }
 
+    #[test]
+    fn test_ts_range_out_of_bounds() {
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, None);
+        ts.add_point(Record::with_timestamp(10, 12.98));
+        ts.add_point(Record::with_timestamp(20, 19.63));
+        ts.add_point(Record::with_timestamp(30, 11.28));
+        assert_eq!(ts.range(15, u128::MAX).len(), 2);
+        assert_eq!(ts.range(0, 5).len(), 0);
+        assert_eq!(ts.range(30, 10).len(), 0);
+        assert_eq!(ts.range_iter(..0).count(), 0);
+        assert_eq!(
+            ts.range_iter((Bound::Excluded(u128::MAX), Bound::Unbounded))
+                .count(),
+            0
+        );
+        assert_eq!(ts.range_rev(35..).count(), 0);
+        let empty = TimeSeries::new("empty-ts".to_string(), None, None);
+        assert!(empty.range(0, u128::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_ts_range_iter() {
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, None);
+        for i in 0..(CHUNK_SIZE as u128 * 3 + 10) {
+            ts.add_point(Record::with_timestamp(i * 10, i as f64));
+        }
+        let timestamps = |it: &mut dyn Iterator<Item = Record>| {
+            return it.map(|r| r.timestamp).collect::<Vec<u128>>();
+        };
+        assert_eq!(timestamps(&mut ts.range_iter(20..50)), vec![20, 30, 40]);
+        assert_eq!(
+            timestamps(&mut ts.range_iter(20..=50)),
+            vec![20, 30, 40, 50]
+        );
+        assert_eq!(timestamps(&mut ts.range_rev(20..=50)), vec![50, 40, 30, 20]);
+        assert_eq!(timestamps(&mut ts.range_rev(..=15)), vec![10, 0]);
+        let forward: Vec<Record> = ts.range_iter(1200..3900).collect();
+        let mut backward: Vec<Record> = ts.range_rev(1200..3900).collect();
+        backward.reverse();
+        assert_eq!(forward.len(), 270);
+        assert_eq!(forward, backward);
+        // Offset and limit are just adapters on top of the lazy iterator
+        let page: Vec<u128> = ts
+            .range_rev(..)
+            .skip(5)
+            .take(3)
+            .map(|r| r.timestamp)
+            .collect();
+        assert_eq!(page, vec![3880, 3870, 3860]);
+    }
+
     #[test]
     fn test_record_new() {
         let r = Record::new(12.98);
         assert_eq!(r.value, 12.98);
     }
+
+    #[test]
+    fn test_record_with_timestamp() {
+        let r = Record::with_timestamp(1000, 12.98);
+        assert_eq!(r.timestamp, 1000);
+        assert_eq!(r.value, 12.98);
+    }
+
+    fn chunked_series(n: u128) -> TimeSeries {
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, None);
+        for i in 0..n {
+            ts.add_point(Record::with_timestamp(i * 10, i as f64));
+        }
+        return ts;
+    }
+
+    #[test]
+    fn test_ts_chunks() {
+        let ts = chunked_series(1000);
+        assert_eq!(ts.chunks.len(), 1000 / CHUNK_SIZE);
+        assert_eq!(ts.head.len(), 1000 % CHUNK_SIZE);
+        assert_eq!(ts.len(), 1000);
+        assert_eq!(get(&ts, 500).unwrap(), Record::with_timestamp(5000, 500.0));
+        assert_eq!(min(&ts), Some(0.0));
+        assert_eq!(max(&ts), Some(999.0));
+        assert_eq!(avg(&ts), Some(499.5));
+        assert_eq!(search(&ts, 5000), 500);
+        let range = ts.range(1275, 1305);
+        assert_eq!(range.len(), 3);
+        assert_eq!(range[0].timestamp, 1280);
+        assert_eq!(range[2].timestamp, 1300);
+    }
+
+    #[test]
+    fn test_ts_chunks_summary() {
+        let mut ts = chunked_series(1000);
+        for (lo, hi) in &[
+            (0, 9990),
+            (5, 9985),
+            (1275, 1305),
+            (1300, 2600),
+            (9000, 20_000),
+        ] {
+            let records = ts.range(*lo, *hi);
+            let expected = Summary::of(records.iter().map(|r| r.value));
+            assert_eq!(ts.summary(*lo, *hi), expected);
+        }
+        assert!(ts.summary(20_000, 30_000).is_none());
+        assert_eq!(ts.aggregate(1275, 1305, Aggregator::Avg), Some(129.0));
+        // Summaries follow late points and retention
+        ts.add_point(Record::with_timestamp(15, 1000.0));
+        assert_eq!(max(&ts), Some(1000.0));
+        ts.retention = Some(Retention::Count(500));
+        ts.expire(0);
+        assert_eq!(max(&ts), Some(999.0));
+        assert_eq!(ts.summary(0, u128::MAX).unwrap().count, 500);
+        assert_eq!(ts.aggregate(0, u128::MAX, Aggregator::First), Some(500.0));
+    }
+
+    fn assert_stats(ts: &TimeSeries) {
+        let summary = ts.summary(0, u128::MAX);
+        let stats = ts.stats();
+        assert_eq!(stats.count, ts.len());
+        assert_eq!(stats.min, summary.map(|s| s.min));
+        assert_eq!(stats.max, summary.map(|s| s.max));
+        assert_eq!(stats.latest, ts.iter().last());
+        assert_eq!(stats.mean(), summary.map(|s| s.sum / s.count as f64));
+    }
+
+    #[test]
+    fn test_ts_stats() {
+        let mut ts = TimeSeries::new("test-ts".to_string(), Some(Retention::Count(300)), None);
+        assert_stats(&ts);
+        for i in 0..1000 {
+            // Alternate extremes at the oldest end, so they're trimmed away
+            let value = if i % 2 == 0 { i as f64 } else { -(i as f64) };
+            ts.add_point(Record::with_timestamp(i * 10, value));
+            assert_stats(&ts);
+        }
+        ts.add_point(Record::with_timestamp(7005, 5000.0));
+        assert_stats(&ts);
+        let mut ts = TimeSeries::new("test-ts".to_string(), Some(Retention::Age(100)), None);
+        ts.add_point(Record::with_timestamp(10, 1.0));
+        ts.add_point(Record::with_timestamp(50, 5.0));
+        ts.expire(1000);
+        ts.expire(1100);
+        assert_stats(&ts);
+        assert_eq!(ts.stats().min, Some(5.0));
+        ts.expire(2000);
+        assert_eq!(*ts.stats(), Stats::default());
+    }
+
+    #[test]
+    fn test_ts_stats_duplicate_policy() {
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, Some(DuplicatePolicy::Last));
+        ts.add_point(Record::with_timestamp(10, 1.0));
+        ts.add_point(Record::with_timestamp(20, 5.0));
+        ts.add_point(Record::with_timestamp(20, 3.0));
+        assert_stats(&ts);
+        assert_eq!(ts.stats().max, Some(3.0));
+        assert_eq!(ts.stats().latest, Some(Record::with_timestamp(20, 3.0)));
+        ts.add_point(Record::with_timestamp(10, 0.5));
+        assert_stats(&ts);
+    }
+
+    #[test]
+    fn test_ts_chunks_out_of_order() {
+        let mut ts = chunked_series(1000);
+        ts.add_point(Record::with_timestamp(15, 1.5));
+        ts.add_point(Record::with_timestamp(2005, 200.5));
+        assert_eq!(ts.len(), 1002);
+        let timestamps: Vec<u128> = ts.iter().map(|r| r.timestamp).collect();
+        let mut sorted = timestamps.clone();
+        sorted.sort();
+        assert_eq!(timestamps, sorted);
+        assert_eq!(get(&ts, 2).unwrap(), Record::with_timestamp(15, 1.5));
+    }
+
+    #[test]
+    fn test_ts_chunks_duplicate_policy() {
+        let mut ts = TimeSeries::new("test-ts".to_string(), None, Some(DuplicatePolicy::Block));
+        for i in 0..1000 {
+            ts.add_point(Record::with_timestamp(i * 10, i as f64));
+        }
+        assert!(ts.rejects(0));
+        assert!(ts.rejects(1270));
+        assert!(ts.rejects(1280));
+        assert!(!ts.rejects(1285));
+        assert!(!ts.add_point(Record::with_timestamp(1270, 1.0)));
+        assert_eq!(ts.len(), 1000);
+    }
+
+    #[test]
+    fn test_ts_chunks_retention() {
+        let mut ts = TimeSeries::new("test-ts".to_string(), Some(Retention::Count(300)), None);
+        for i in 0..1000 {
+            ts.add_point(Record::with_timestamp(i * 10, i as f64));
+        }
+        assert_eq!(ts.len(), 300);
+        assert_eq!(get(&ts, 0).unwrap().timestamp, 7000);
+        let mut ts = TimeSeries::new("test-ts".to_string(), Some(Retention::Age(3000)), None);
+        for i in 0..1000 {
+            ts.add_point(Record::with_timestamp(i * 10, i as f64));
+        }
+        assert_eq!(ts.len(), 301);
+        assert_eq!(get(&ts, 0).unwrap().timestamp, 6990);
+        ts.expire(100_000);
+        ts.expire(102_010);
+        assert_eq!(ts.len(), 100);
+        assert_eq!(get(&ts, 0).unwrap().timestamp, 9000);
+        ts.expire(110_010);
+        assert!(ts.is_empty());
+    }
 }
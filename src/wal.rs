@@ -0,0 +1,294 @@
+// BSD 2-Clause License
+//
+// Copyright (c) 2020, Andrea Giacomo Baldan
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+//   list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+//   this list of conditions and the following disclaimer in the documentation
+//   and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::index::Labels;
+use crate::timeseries::{DuplicatePolicy, Record, Retention};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// Size of the fixed part of every entry on disk, a u32 with the length of the payload followed by
+// a u32 with its CRC32 checksum
+const ENTRY_HEADER_SIZE: usize = 8;
+
+// Every mutation of the keyspace, logged before being applied. Points carry their final timestamp
+// so replaying them is deterministic.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum WalEntry {
+    Create {
+        name: String,
+        labels: Labels,
+        retention: Option<Retention>,
+        duplicate_policy: Option<DuplicatePolicy>,
+    },
+    Delete {
+        name: String,
+    },
+    AddPoints {
+        points: Vec<(String, Record)>,
+    },
+}
+
+// When to flush the log to the disk: after every entry, at most once per second or never,
+// leaving it to the OS
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum FsyncPolicy {
+    Always,
+    EverySecond,
+    Never,
+}
+
+// Append-only write-ahead log, a sequence of checksummed entries each one tagged with an
+// increasing log sequence number
+pub struct Wal {
+    file: File,
+    fsync: FsyncPolicy,
+    last_sync: Instant,
+    dirty: bool,
+}
+
+impl Wal {
+    pub fn open(path: &Path, fsync: FsyncPolicy) -> Result<Wal, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Wal {
+            file,
+            fsync,
+            last_sync: Instant::now(),
+            dirty: false,
+        })
+    }
+
+    pub fn append(&mut self, lsn: u64, entry: &WalEntry) -> Result<(), Error> {
+        let payload =
+            bincode::serialize(&(lsn, entry)).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let mut buf = Vec::with_capacity(ENTRY_HEADER_SIZE + payload.len());
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        buf.extend_from_slice(&payload);
+        // A single write per entry, a crash can only leave a partial entry at the tail
+        self.file.write_all(&buf)?;
+        self.dirty = true;
+        if self.fsync == FsyncPolicy::Always {
+            return self.sync();
+        }
+        return Ok(());
+    }
+
+    // Flush pending entries to the disk if the policy is to do it every second, meant to be called
+    // periodically
+    pub fn tick(&mut self) -> Result<(), Error> {
+        if self.fsync == FsyncPolicy::EverySecond
+            && self.last_sync.elapsed() >= Duration::from_secs(1)
+        {
+            return self.sync();
+        }
+        return Ok(());
+    }
+
+    // Drop every entry, to be called once they're all part of a snapshot
+    pub fn truncate(&mut self) -> Result<(), Error> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.dirty = false;
+        return Ok(());
+    }
+
+    pub fn sync(&mut self) -> Result<(), Error> {
+        if self.dirty {
+            self.file.sync_data()?;
+            self.dirty = false;
+        }
+        self.last_sync = Instant::now();
+        return Ok(());
+    }
+
+    // Read back every valid entry of the log at path. A truncated or corrupted tail, like the one
+    // left by a crash in the middle of a write, is dropped from the file with a warning, so new
+    // entries are appended right after the last valid one.
+    pub fn replay(path: &Path) -> Result<Vec<(u64, WalEntry)>, Error> {
+        let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset < buf.len() {
+            match decode_entry(&buf[offset..]) {
+                Some((entry, len)) => {
+                    entries.push(entry);
+                    offset += len;
+                }
+                None => {
+                    warn!(
+                        "Corrupted write-ahead log entry at offset {}, skipping {} bytes",
+                        offset,
+                        buf.len() - offset
+                    );
+                    file.set_len(offset as u64)?;
+                    break;
+                }
+            }
+        }
+        return Ok(entries);
+    }
+}
+
+// Decode the entry at the start of the buffer returning it along with its size on disk, None if
+// it is incomplete or its checksum doesn't match
+fn decode_entry(b: &[u8]) -> Option<((u64, WalEntry), usize)> {
+    if b.len() < ENTRY_HEADER_SIZE {
+        return None;
+    }
+    let mut word = [0_u8; 4];
+    word.copy_from_slice(&b[0..4]);
+    let len = u32::from_le_bytes(word) as usize;
+    word.copy_from_slice(&b[4..8]);
+    let crc = u32::from_le_bytes(word);
+    let payload = b.get(ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE + len)?;
+    if crc32fast::hash(payload) != crc {
+        return None;
+    }
+    let entry = bincode::deserialize(payload).ok()?;
+    return Some((entry, ENTRY_HEADER_SIZE + len));
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn wal_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("teaspoon-{}-{}.wal", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        return path;
+    }
+
+    fn entries() -> Vec<(u64, WalEntry)> {
+        return vec![
+            (
+                1,
+                WalEntry::Create {
+                    name: "test-ts".to_string(),
+                    labels: Labels::new(),
+                    retention: Some(Retention::Count(10)),
+                    duplicate_policy: None,
+                },
+            ),
+            (
+                2,
+                WalEntry::AddPoints {
+                    points: vec![("test-ts".to_string(), Record::with_timestamp(10, 12.98))],
+                },
+            ),
+            (
+                3,
+                WalEntry::Delete {
+                    name: "test-ts".to_string(),
+                },
+            ),
+        ];
+    }
+
+    #[test]
+    fn test_wal_replay() {
+        let path = wal_path("replay");
+        let mut wal = Wal::open(&path, FsyncPolicy::Always).unwrap();
+        for (lsn, e) in entries() {
+            wal.append(lsn, &e).unwrap();
+        }
+        assert_eq!(Wal::replay(&path).unwrap(), entries());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_wal_replay_missing() {
+        let path = wal_path("missing");
+        assert!(Wal::replay(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_wal_replay_truncated_tail() {
+        let path = wal_path("truncated");
+        let mut wal = Wal::open(&path, FsyncPolicy::Never).unwrap();
+        for (lsn, e) in entries() {
+            wal.append(lsn, &e).unwrap();
+        }
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        assert_eq!(Wal::replay(&path).unwrap(), entries()[..2].to_vec());
+        // The partial entry is gone, new ones follow the last valid entry
+        let mut wal = Wal::open(&path, FsyncPolicy::Never).unwrap();
+        wal.append(3, &entries()[2].1).unwrap();
+        assert_eq!(Wal::replay(&path).unwrap(), entries());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_wal_replay_corrupted_tail() {
+        let path = wal_path("corrupted");
+        let mut wal = Wal::open(&path, FsyncPolicy::Never).unwrap();
+        for (lsn, e) in entries() {
+            wal.append(lsn, &e).unwrap();
+        }
+        let mut buf = fs::read(&path).unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 0xff;
+        fs::write(&path, buf).unwrap();
+        assert_eq!(Wal::replay(&path).unwrap(), entries()[..2].to_vec());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_wal_truncate() {
+        let path = wal_path("truncate");
+        let mut wal = Wal::open(&path, FsyncPolicy::Never).unwrap();
+        for (lsn, e) in entries() {
+            wal.append(lsn, &e).unwrap();
+        }
+        wal.truncate().unwrap();
+        assert!(Wal::replay(&path).unwrap().is_empty());
+        wal.append(4, &entries()[2].1).unwrap();
+        assert_eq!(
+            Wal::replay(&path).unwrap(),
+            vec![(4, entries()[2].1.clone())]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}